@@ -0,0 +1,141 @@
+//! Which keys each user may read or write.
+//!
+//! Rules come from a TOML file, e.g.
+//!
+//! ```toml
+//! [[rules]]
+//! roles = ["viewer"]
+//! keys = ["status", "sensor:*"]
+//!
+//! [[rules]]
+//! users = ["alice"]
+//! keys = ["*"]
+//! access = "write"
+//! ```
+//!
+//! A user may read a key if a rule naming them (or `"*"`), or one of their
+//! roles, has a glob matching it; writing needs a rule with `access = "write"`.
+//! Without a rules file every user may do anything.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::RwLock
+};
+
+use serde::Deserialize;
+
+use crate::auth::Identity;
+
+/// What a rule lets its users do, each level including those before
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    #[default]
+    Read,
+    Write
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    keys: Vec<String>,
+    #[serde(default)]
+    access: Access
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>
+}
+
+struct Rule {
+    users: Vec<String>,
+    roles: Vec<String>,
+    keys: Vec<glob::Pattern>,
+    access: Access
+}
+
+impl Rule {
+    fn from(config: RuleConfig) -> Result<Rule, glob::PatternError> {
+        Ok(Rule {
+            users: config.users,
+            roles: config.roles,
+            keys: config.keys.iter().map(|key| glob::Pattern::new(key)).collect::<Result<_, _>>()?,
+            access: config.access
+        })
+    }
+
+    fn applies_to(&self, identity: &Identity) -> bool {
+        self.users.iter().any(|user| user == "*" || *user == identity.user)
+            || self.roles.iter().any(|role| identity.roles.contains(role))
+    }
+}
+
+fn read_rules(path: &Path) -> io::Result<Vec<Rule>> {
+    let invalid = |err: String| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), err)
+    );
+    let text = fs::read_to_string(path)?;
+    let file: RulesFile = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+    file.rules.into_iter()
+        .map(|rule| Rule::from(rule).map_err(|err| invalid(err.to_string())))
+        .collect()
+}
+
+/// The access rules, shared by the broker and every HTTP worker
+pub struct AccessControl {
+    /// where the rules are read from, `None` to allow everything
+    path: Option<PathBuf>,
+    rules: RwLock<Vec<Rule>>
+}
+
+impl AccessControl {
+    pub fn load(path: Option<&Path>) -> io::Result<AccessControl> {
+        let rules = match path {
+            Some(path) => read_rules(path)?,
+            None => Vec::new()
+        };
+        Ok(AccessControl {
+            path: path.map(Path::to_path_buf),
+            rules: RwLock::new(rules)
+        })
+    }
+
+    /// Read the rules file again, keeping the rules as they were if it
+    /// can't be read
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let rules = read_rules(path)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// Whether `identity` may have `access` to `key`
+    pub fn allows(&self, identity: &Identity, key: &str, access: Access) -> bool {
+        if self.path.is_none() {
+            return true;
+        }
+        self.rules.read().unwrap().iter().any(
+            |rule| rule.access >= access
+                && rule.applies_to(identity)
+                && rule.keys.iter().any(|pattern| pattern.matches(key))
+        )
+    }
+
+    /// Those of `keys` that `identity` may read
+    pub fn readable(&self, identity: &Identity, keys: &HashSet<String>) -> HashSet<String> {
+        keys.iter().filter(|key| self.allows(identity, key, Access::Read)).cloned().collect()
+    }
+}
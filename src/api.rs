@@ -0,0 +1,159 @@
+//! Plain HTTP reads, for scripts and health checks that don't hold a socket.
+//!
+//! Every read goes through the broker, over its connection to Redis, and
+//! needs the same credentials as a websocket.
+
+use actix_web::{
+    http::{header::{self, ContentType}, StatusCode},
+    web,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse
+};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    acl::{Access, AccessControl},
+    auth::Identity,
+    server::{
+        RedisHashBroker,
+        client::{ErrorCode, ErrorMessage},
+        query::{Query, QueryAnswer, QueryError}
+    }
+};
+
+/// Keys returned per page when the request doesn't say
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Most keys a request may ask for per page
+const MAX_PAGE_SIZE: usize = 1000;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/hash/{name}", web::get().to(hash))
+        .route("/hash/{name}/{field}", web::get().to(field))
+        .route("/keys", web::get().to(keys));
+}
+
+/// The answer to a read of `key` the user may not make
+fn forbidden(key: &str) -> HttpResponse {
+    let mut message = ErrorMessage::new(ErrorCode::Forbidden, "not allowed to read this key".to_string());
+    message.key = Some(key.to_string());
+    HttpResponse::Forbidden().json(message)
+}
+
+/// The answer to a query the broker answered with the wrong kind of answer
+fn unexpected_answer() -> HttpResponse {
+    log::error!("query answered with something else");
+    HttpResponse::InternalServerError().json(
+        ErrorMessage::new(ErrorCode::InternalError, "unexpected answer to the query".to_string())
+    )
+}
+
+fn error_response(err: QueryError) -> HttpResponse {
+    let (status, code) = match &err {
+        QueryError::MissingKey => (StatusCode::NOT_FOUND, ErrorCode::MissingKey),
+        QueryError::MissingField => (StatusCode::NOT_FOUND, ErrorCode::MissingField),
+        QueryError::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::RedisUnavailable),
+        QueryError::Redis(err) => match ErrorCode::of_redis_error(err) {
+            ErrorCode::WrongType => (StatusCode::CONFLICT, ErrorCode::WrongType),
+            ErrorCode::RedisUnavailable => (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::RedisUnavailable),
+            code => (StatusCode::BAD_GATEWAY, code)
+        }
+    };
+    HttpResponse::build(status).json(ErrorMessage::new(code, err.to_string()))
+}
+
+/// Every field of hash `name`, tagged so unchanged contents needn't be sent again
+async fn hash(
+    req: HttpRequest,
+    name: web::Path<String>,
+    identity: Identity,
+    acl: web::Data<AccessControl>,
+    srv: web::Data<RedisHashBroker>,
+) -> HttpResponse {
+    let name = name.into_inner();
+    if !acl.allows(&identity, &name, Access::Read) {
+        return forbidden(&name);
+    }
+    let contents = match srv.query(Query::Hash(name)).await {
+        Ok(QueryAnswer::Hash(contents)) => contents,
+        Ok(_) => return unexpected_answer(),
+        Err(err) => return error_response(err)
+    };
+
+    // fields come in order, so equal contents serialise, and hash, alike
+    // across releases too
+    let body = serde_json::to_vec(&contents).expect("a map of strings serialises");
+    let digest: String = Sha1::digest(&body).iter().map(|byte| format!("{byte:02x}")).collect();
+    let etag = header::EntityTag::new_weak(digest);
+    let unchanged = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false
+    };
+    if unchanged {
+        return HttpResponse::NotModified().insert_header(header::ETag(etag)).finish();
+    }
+    HttpResponse::Ok()
+        .insert_header(header::ETag(etag))
+        .content_type(ContentType::json())
+        .body(body)
+}
+
+/// One field of a hash, as a JSON string
+async fn field(
+    path: web::Path<(String, String)>,
+    identity: Identity,
+    acl: web::Data<AccessControl>,
+    srv: web::Data<RedisHashBroker>,
+) -> HttpResponse {
+    let (hash, field) = path.into_inner();
+    if !acl.allows(&identity, &hash, Access::Read) {
+        return forbidden(&hash);
+    }
+    match srv.query(Query::Field { hash, field }).await {
+        Ok(answer) => HttpResponse::Ok().json(answer),
+        Err(err) => error_response(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct KeysQuery {
+    /// glob pattern the keys match
+    #[serde(default = "KeysQuery::every_key")]
+    pattern: String,
+    /// only keys of this type, e.g. `hash`
+    #[serde(rename = "type")]
+    key_type: Option<String>,
+    /// `cursor` of the previous page, 0 for the first
+    #[serde(default)]
+    cursor: u64,
+    count: Option<usize>
+}
+
+impl KeysQuery {
+    fn every_key() -> String {
+        String::from("*")
+    }
+}
+
+/// A page of the keys matching a pattern, with the cursor of the next;
+/// keys the user may not read are left out
+async fn keys(
+    query: web::Query<KeysQuery>,
+    identity: Identity,
+    acl: web::Data<AccessControl>,
+    srv: web::Data<RedisHashBroker>,
+) -> HttpResponse {
+    let KeysQuery { pattern, key_type, cursor, count } = query.into_inner();
+    let count = count.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    match srv.query(Query::Keys { pattern, key_type, cursor, count }).await {
+        Ok(QueryAnswer::Keys { mut keys, cursor }) => {
+            keys.retain(|key| acl.allows(&identity, key, Access::Read));
+            HttpResponse::Ok().json(QueryAnswer::Keys { keys, cursor })
+        },
+        Ok(_) => unexpected_answer(),
+        Err(err) => error_response(err)
+    }
+}
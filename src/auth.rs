@@ -0,0 +1,281 @@
+//! Who is making a request.
+//!
+//! Requests carry a bearer token, in the `Authorization` header or, where
+//! browsers can't set one (websockets, `EventSource`), as `access_token` in
+//! the query. A token is either one of the static ones configured or an
+//! HS256 JWT. `POST /login` exchanges a token for a cookie that serves the
+//! same purpose.
+
+use std::{
+    fmt,
+    future::{ready, Ready}
+};
+
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    dev::{Payload, ServiceRequest},
+    http::{header, StatusCode},
+    web,
+    FromRequest,
+    HttpRequest,
+    HttpResponse,
+    ResponseError
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{AuthConfig, TokenConfig},
+    server::client::{ErrorCode, ErrorMessage}
+};
+
+/// Name of the cookie `POST /login` sets
+const SESSION_COOKIE: &str = "hashboard_session";
+
+/// Who a request was made by, `anonymous` when authentication is off
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub user: String,
+    #[serde(default)]
+    pub roles: Vec<String>
+}
+
+impl Identity {
+    pub fn anonymous() -> Identity {
+        Identity {
+            user: String::from("anonymous"),
+            roles: Vec::new()
+        }
+    }
+}
+
+/// What JWTs and login cookies hold
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    exp: u64
+}
+
+impl From<Claims> for Identity {
+    fn from(claims: Claims) -> Self {
+        Identity {
+            user: claims.sub,
+            roles: claims.roles
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// the request carries no credentials
+    Missing,
+    /// the credentials are unknown, expired or forged
+    Invalid,
+    /// a login cookie came with a request from another site's page
+    ForeignOrigin
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => f.write_str("authentication required"),
+            AuthError::Invalid => f.write_str("invalid or expired credentials"),
+            AuthError::ForeignOrigin => f.write_str("login cookie not accepted from this origin")
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::ForeignOrigin => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let AuthError::ForeignOrigin = self {
+            return HttpResponse::Forbidden().json(ErrorMessage::new(ErrorCode::Forbidden, self.to_string()));
+        }
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .json(ErrorMessage::new(ErrorCode::Unauthorized, self.to_string()))
+    }
+}
+
+/// Compare secrets in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The bearer token `req` carries, if any
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    if let Some(token) = header.and_then(|value| value.strip_prefix("Bearer ")) {
+        return Some(token.trim().to_string());
+    }
+    let query = web::Query::<Vec<(String, String)>>::from_query(req.query_string()).ok()?;
+    query.into_inner().into_iter()
+        .find(|(name, _)| name == "access_token")
+        .map(|(_, token)| token)
+}
+
+/// The request line of `req` with any `access_token` blanked, for the access log
+pub fn redacted_request_line(req: &ServiceRequest) -> String {
+    let query = req.query_string().split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("access_token", _)) => "access_token=***",
+            _ => pair
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let target = if query.is_empty() { req.path().to_string() } else { format!("{}?{query}", req.path()) };
+    format!("{} {target} {:?}", req.method(), req.version())
+}
+
+/// Checks credentials, shared by every HTTP worker
+pub struct Authenticator {
+    tokens: Vec<TokenConfig>,
+    jwt_key: Option<DecodingKey>,
+    cookie_encoding_key: EncodingKey,
+    cookie_decoding_key: DecodingKey,
+    session_ttl_s: u64,
+    /// origins besides the server's own the login cookie is honoured from
+    allowed_origins: Vec<String>
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Authenticator {
+        let cookie_secret = match &config.cookie_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            // cookies then last until the next restart at most
+            None => rand::thread_rng().gen::<[u8; 32]>().to_vec()
+        };
+        Authenticator {
+            tokens: config.tokens.clone(),
+            jwt_key: config.jwt_secret.as_ref().map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            cookie_encoding_key: EncodingKey::from_secret(&cookie_secret),
+            cookie_decoding_key: DecodingKey::from_secret(&cookie_secret),
+            session_ttl_s: config.session_ttl_s,
+            allowed_origins: config.allowed_origins.iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect()
+        }
+    }
+
+    /// Whether requests have to authenticate at all
+    fn is_required(&self) -> bool {
+        !self.tokens.is_empty() || self.jwt_key.is_some()
+    }
+
+    /// Whether `req` comes from a page of this server or of one of
+    /// `auth.allowed_origins`. Browsers always name the origin of websockets
+    /// and cross-site requests; without one it is not a browser's or not
+    /// cross-site
+    fn allows_origin(&self, req: &HttpRequest) -> bool {
+        let Some(origin) = req.headers().get(header::ORIGIN) else {
+            return true;
+        };
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            return true;
+        }
+        // `<scheme>://<host>[:<port>]` against the host the request was sent to
+        origin.split_once("://")
+            .is_some_and(|(_, host)| host.eq_ignore_ascii_case(req.connection_info().host()))
+    }
+
+    fn decode(token: &str, key: &DecodingKey) -> Result<Identity, AuthError> {
+        jsonwebtoken::decode::<Claims>(token, key, &Validation::new(Algorithm::HS256))
+            .map(|data| data.claims.into())
+            .map_err(|_| AuthError::Invalid)
+    }
+
+    /// The identity `token` stands for
+    fn check_token(&self, token: &str) -> Result<Identity, AuthError> {
+        let configured = self.tokens.iter().find(
+            |configured| constant_time_eq(configured.token.as_bytes(), token.as_bytes())
+        );
+        if let Some(configured) = configured {
+            return Ok(Identity {
+                user: configured.user.clone(),
+                roles: configured.roles.clone()
+            });
+        }
+        match &self.jwt_key {
+            Some(key) => Authenticator::decode(token, key),
+            None => Err(AuthError::Invalid)
+        }
+    }
+
+    /// Who made `req`, from its bearer token or else its login cookie
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Identity, AuthError> {
+        if !self.is_required() {
+            return Ok(Identity::anonymous());
+        }
+        if let Some(token) = bearer_token(req) {
+            return self.check_token(&token);
+        }
+        match req.cookie(SESSION_COOKIE) {
+            // another site's page can open a websocket that carries the cookie
+            Some(_) if !self.allows_origin(req) => Err(AuthError::ForeignOrigin),
+            Some(cookie) => Authenticator::decode(cookie.value(), &self.cookie_decoding_key),
+            None => Err(AuthError::Missing)
+        }
+    }
+
+    /// A login cookie standing for `identity`
+    fn session_cookie(&self, identity: &Identity) -> Cookie<'static> {
+        let claims = Claims {
+            sub: identity.user.clone(),
+            roles: identity.roles.clone(),
+            exp: jsonwebtoken::get_current_timestamp() + self.session_ttl_s
+        };
+        let value = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.cookie_encoding_key)
+            .expect("HS256 signing does not fail");
+        Cookie::build(SESSION_COOKIE, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(self.session_ttl_s.try_into().unwrap_or(i64::MAX)))
+            .finish()
+    }
+}
+
+/// Handlers taking an `Identity` only run for authenticated requests
+impl FromRequest for Identity {
+    type Error = AuthError;
+    type Future = Ready<Result<Identity, AuthError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.app_data::<web::Data<Authenticator>>() {
+            Some(authenticator) => authenticator.authenticate(req),
+            None => Ok(Identity::anonymous())
+        })
+    }
+}
+
+/// Exchange a bearer token for a login cookie, for browsers
+pub async fn login(
+    req: HttpRequest,
+    authenticator: web::Data<Authenticator>,
+) -> Result<HttpResponse, AuthError> {
+    let identity = match bearer_token(&req) {
+        Some(token) => authenticator.check_token(&token)?,
+        None if !authenticator.is_required() => Identity::anonymous(),
+        None => return Err(AuthError::Missing)
+    };
+    Ok(HttpResponse::Ok().cookie(authenticator.session_cookie(&identity)).json(identity))
+}
+
+/// Drop the login cookie
+pub async fn logout() -> HttpResponse {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    HttpResponse::Ok().cookie(cookie).finish()
+}
@@ -0,0 +1,434 @@
+//! Runtime configuration for the hashboard.
+//!
+//! Every setting can come from three places. In order of precedence (highest first):
+//!
+//! 1. command line flags, e.g. `--redis-url redis://localhost:6379`
+//! 2. environment variables, e.g. `HASHBOARD_REDIS_URL=redis://localhost:6379`
+//! 3. the TOML file named by `--config` / `HASHBOARD_CONFIG`
+//!
+//! Anything left unset falls back to the defaults below, which match the
+//! docker-compose deployment.
+
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}};
+
+use clap::Parser;
+use redis::{ConnectionInfo, IntoConnectionInfo, RedisResult};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub http: HttpConfig,
+    pub redis: RedisConfig,
+    pub broker: BrokerConfig,
+    pub auth: AuthConfig
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address the HTTP server binds to
+    pub bind: String,
+    pub port: u16,
+    /// Number of actix worker threads
+    pub workers: usize,
+    /// Messages to clients longer than this many bytes are split into
+    /// websocket continuation frames, 0 never splits them
+    pub chunk_size: usize,
+    /// How long `/readyz` waits for the broker to ping Redis
+    pub readiness_timeout_ms: u64,
+    /// How long shutting down may take before open connections are dropped
+    pub drain_timeout_ms: u64,
+    /// How long clients are told to wait before reconnecting after a shutdown
+    pub retry_after_ms: u64,
+    /// Serve `/metrics` without credentials, though it names the keys watched
+    pub metrics_public: bool
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            bind: String::from("0.0.0.0"),
+            port: 8080,
+            workers: 2,
+            chunk_size: 65536,
+            readiness_timeout_ms: 1000,
+            drain_timeout_ms: 10000,
+            retry_after_ms: 5000,
+            metrics_public: false
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// Connection URL, `redis://[<username>][:<password>@]<host>[:<port>][/<db>]`
+    pub url: String,
+    /// Database index, overrides any given in `url`
+    pub db: Option<i64>,
+    /// Username, overrides any given in `url`
+    pub username: Option<String>,
+    /// Password, overrides any given in `url`
+    pub password: Option<String>,
+    /// Name set on the connection with `CLIENT SETNAME`
+    pub client_name: Option<String>,
+    /// How long to wait for a connection to be established
+    pub connect_timeout_ms: u64,
+    /// How long to wait for a reply before considering the connection lost
+    pub command_timeout_ms: u64,
+    /// Delay before the first reconnection attempt, doubling with each failure
+    pub reconnect_initial_ms: u64,
+    /// Longest delay between reconnection attempts
+    pub reconnect_max_ms: u64
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            url: String::from("redis://redishost:6379"),
+            db: None,
+            username: None,
+            password: None,
+            client_name: Some(String::from("redis_hashboard")),
+            connect_timeout_ms: 5000,
+            command_timeout_ms: 5000,
+            reconnect_initial_ms: 100,
+            reconnect_max_ms: 10000
+        }
+    }
+}
+
+// hand-written so the password never ends up in a log line
+impl std::fmt::Debug for RedisConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisConfig")
+            .field("url", &self.url)
+            .field("db", &self.db)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("client_name", &self.client_name)
+            .field("connect_timeout_ms", &self.connect_timeout_ms)
+            .field("command_timeout_ms", &self.command_timeout_ms)
+            .field("reconnect_initial_ms", &self.reconnect_initial_ms)
+            .field("reconnect_max_ms", &self.reconnect_max_ms)
+            .finish()
+    }
+}
+
+impl RedisConfig {
+    /// The connection details of `url` with the explicit overrides applied
+    pub fn connection_info(&self) -> RedisResult<ConnectionInfo> {
+        let mut info = self.url.as_str().into_connection_info()?;
+        if let Some(db) = self.db {
+            info.redis.db = db;
+        }
+        if self.username.is_some() {
+            info.redis.username = self.username.clone();
+        }
+        if self.password.is_some() {
+            info.redis.password = self.password.clone();
+        }
+        Ok(info)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    /// Shortest time between two reads of the same hash
+    pub min_refresh_interval_ms: u64,
+    /// Per-hash overrides of `min_refresh_interval_ms`
+    pub refresh_intervals_ms: HashMap<String, u64>,
+    /// Upper bound on hash reads issued to Redis per second, 0 for unlimited
+    pub max_requests_per_second: f64,
+    /// How often keys matching a subscribed pattern are rescanned when polling
+    pub pattern_rescan_interval_ms: u64,
+    /// Re-read hashes only when Redis publishes a keyspace event for them
+    pub keyspace_notifications: bool,
+    /// Allow the broker to switch on `notify-keyspace-events` itself
+    pub configure_keyspace_events: bool,
+    /// How many of a stream's latest entries are read on each refresh
+    pub stream_backlog: usize,
+    /// Hashes with more fields than this are read in pages with HSCAN, 0 reads every hash whole
+    pub hscan_threshold: usize,
+    /// Fields asked for per HSCAN page
+    pub hscan_page_size: usize,
+    /// How long a disconnected client's subscriptions are kept for it to resume, 0 to drop them at once
+    pub resume_grace_ms: u64,
+    /// Messages queued for a client beyond which its updates are held back and merged, 0 for no limit
+    pub outbound_queue_limit: usize
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            min_refresh_interval_ms: 100,
+            refresh_intervals_ms: HashMap::new(),
+            max_requests_per_second: 200.0,
+            pattern_rescan_interval_ms: 5000,
+            keyspace_notifications: false,
+            configure_keyspace_events: false,
+            stream_backlog: 100,
+            hscan_threshold: 10000,
+            hscan_page_size: 1000,
+            resume_grace_ms: 30000,
+            outbound_queue_limit: 100
+        }
+    }
+}
+
+/// A bearer token accepted as a fixed identity
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub token: String,
+    pub user: String,
+    #[serde(default)]
+    pub roles: Vec<String>
+}
+
+/// Who may connect. With no tokens and no JWT secret everyone may, anonymously
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Static bearer tokens
+    pub tokens: Vec<TokenConfig>,
+    /// Secret of the HS256 JWTs accepted as bearer tokens
+    pub jwt_secret: Option<String>,
+    /// Secret login cookies are signed with, a random one on each start if unset
+    pub cookie_secret: Option<String>,
+    /// How long a login cookie lasts
+    pub session_ttl_s: u64,
+    /// Origins besides the server's own whose pages may use the login cookie,
+    /// e.g. `https://dashboard.example.com`
+    pub allowed_origins: Vec<String>,
+    /// Rules on which keys each user may read and write, reread on SIGHUP; everything is allowed without
+    pub acl_file: Option<PathBuf>
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            tokens: Vec::new(),
+            jwt_secret: None,
+            cookie_secret: None,
+            session_ttl_s: 86400,
+            allowed_origins: Vec::new(),
+            acl_file: None
+        }
+    }
+}
+
+// hand-written so no secret ends up in a log line
+impl std::fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("tokens", &self.tokens.iter().map(|token| &token.user).collect::<Vec<_>>())
+            .field("jwt_secret", &self.jwt_secret.as_ref().map(|_| "***"))
+            .field("cookie_secret", &self.cookie_secret.as_ref().map(|_| "***"))
+            .field("session_ttl_s", &self.session_ttl_s)
+            .field("allowed_origins", &self.allowed_origins)
+            .field("acl_file", &self.acl_file)
+            .finish()
+    }
+}
+
+/// Command line flags, each of which may also be given as an environment variable
+#[derive(Debug, Parser)]
+#[command(version, about = "Streams Redis hashes to browsers over websockets")]
+struct Args {
+    /// TOML configuration file
+    #[arg(short, long, env = "HASHBOARD_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address the HTTP server binds to
+    #[arg(long, env = "HASHBOARD_BIND")]
+    bind: Option<String>,
+
+    /// Port the HTTP server listens on
+    #[arg(long, env = "HASHBOARD_PORT")]
+    port: Option<u16>,
+
+    /// Number of HTTP worker threads
+    #[arg(long, env = "HASHBOARD_WORKERS")]
+    workers: Option<usize>,
+
+    /// Size in bytes above which messages to clients are sent in several frames, 0 for never
+    #[arg(long, env = "HASHBOARD_CHUNK_SIZE")]
+    chunk_size: Option<usize>,
+
+    /// Milliseconds /readyz waits for the broker to ping Redis
+    #[arg(long, env = "HASHBOARD_READINESS_TIMEOUT_MS")]
+    readiness_timeout_ms: Option<u64>,
+
+    /// Milliseconds shutting down may take before open connections are dropped
+    #[arg(long, env = "HASHBOARD_DRAIN_TIMEOUT_MS")]
+    drain_timeout_ms: Option<u64>,
+
+    /// Milliseconds clients are told to wait before reconnecting after a shutdown
+    #[arg(long, env = "HASHBOARD_RETRY_AFTER_MS")]
+    retry_after_ms: Option<u64>,
+
+    /// Serve /metrics without credentials
+    #[arg(long, env = "HASHBOARD_METRICS_PUBLIC", num_args = 0..=1, default_missing_value = "true")]
+    metrics_public: Option<bool>,
+
+    /// Redis connection URL
+    #[arg(long, env = "HASHBOARD_REDIS_URL")]
+    redis_url: Option<String>,
+
+    /// Redis database index
+    #[arg(long, env = "HASHBOARD_REDIS_DB")]
+    redis_db: Option<i64>,
+
+    /// Redis username
+    #[arg(long, env = "HASHBOARD_REDIS_USERNAME")]
+    redis_username: Option<String>,
+
+    /// Redis password
+    #[arg(long, env = "HASHBOARD_REDIS_PASSWORD", hide_env_values = true)]
+    redis_password: Option<String>,
+
+    /// Name given to the Redis connection
+    #[arg(long, env = "HASHBOARD_REDIS_CLIENT_NAME")]
+    redis_client_name: Option<String>,
+
+    /// Shortest time between two reads of the same hash, in milliseconds
+    #[arg(long, env = "HASHBOARD_MIN_REFRESH_INTERVAL_MS")]
+    min_refresh_interval_ms: Option<u64>,
+
+    /// Upper bound on hash reads issued to Redis per second, 0 for unlimited
+    #[arg(long, env = "HASHBOARD_MAX_REQUESTS_PER_SECOND")]
+    max_requests_per_second: Option<f64>,
+
+    /// How often keys matching a subscribed pattern are rescanned when polling, in milliseconds
+    #[arg(long, env = "HASHBOARD_PATTERN_RESCAN_INTERVAL_MS")]
+    pattern_rescan_interval_ms: Option<u64>,
+
+    /// Re-read hashes only when Redis publishes a keyspace event for them
+    #[arg(long, env = "HASHBOARD_KEYSPACE_NOTIFICATIONS", num_args = 0..=1, default_missing_value = "true")]
+    keyspace_notifications: Option<bool>,
+
+    /// Allow switching on `notify-keyspace-events` with CONFIG SET
+    #[arg(long, env = "HASHBOARD_CONFIGURE_KEYSPACE_EVENTS", num_args = 0..=1, default_missing_value = "true")]
+    configure_keyspace_events: Option<bool>,
+
+    /// How long a disconnected client's subscriptions are kept for it to resume, in milliseconds
+    #[arg(long, env = "HASHBOARD_RESUME_GRACE_MS")]
+    resume_grace_ms: Option<u64>,
+
+    /// Messages queued for a client beyond which its updates are held back, 0 for no limit
+    #[arg(long, env = "HASHBOARD_OUTBOUND_QUEUE_LIMIT")]
+    outbound_queue_limit: Option<usize>,
+
+    /// Secret of the HS256 JWTs accepted as bearer tokens
+    #[arg(long, env = "HASHBOARD_JWT_SECRET", hide_env_values = true)]
+    jwt_secret: Option<String>,
+
+    /// Secret login cookies are signed with
+    #[arg(long, env = "HASHBOARD_COOKIE_SECRET", hide_env_values = true)]
+    cookie_secret: Option<String>,
+
+    /// File of rules on which keys each user may read and write
+    #[arg(long, env = "HASHBOARD_ACL_FILE")]
+    acl_file: Option<PathBuf>,
+}
+
+impl Args {
+    /// Overwrite the settings of `config` with those given as flags/environment
+    fn apply(self, config: &mut Config) {
+        if let Some(bind) = self.bind {
+            config.http.bind = bind;
+        }
+        if let Some(port) = self.port {
+            config.http.port = port;
+        }
+        if let Some(workers) = self.workers {
+            config.http.workers = workers;
+        }
+        if let Some(size) = self.chunk_size {
+            config.http.chunk_size = size;
+        }
+        if let Some(timeout) = self.readiness_timeout_ms {
+            config.http.readiness_timeout_ms = timeout;
+        }
+        if let Some(timeout) = self.drain_timeout_ms {
+            config.http.drain_timeout_ms = timeout;
+        }
+        if let Some(delay) = self.retry_after_ms {
+            config.http.retry_after_ms = delay;
+        }
+        if let Some(public) = self.metrics_public {
+            config.http.metrics_public = public;
+        }
+        if let Some(url) = self.redis_url {
+            config.redis.url = url;
+        }
+        if self.redis_db.is_some() {
+            config.redis.db = self.redis_db;
+        }
+        if self.redis_username.is_some() {
+            config.redis.username = self.redis_username;
+        }
+        if self.redis_password.is_some() {
+            config.redis.password = self.redis_password;
+        }
+        if self.redis_client_name.is_some() {
+            config.redis.client_name = self.redis_client_name;
+        }
+        if let Some(interval) = self.min_refresh_interval_ms {
+            config.broker.min_refresh_interval_ms = interval;
+        }
+        if let Some(rate) = self.max_requests_per_second {
+            config.broker.max_requests_per_second = rate;
+        }
+        if let Some(interval) = self.pattern_rescan_interval_ms {
+            config.broker.pattern_rescan_interval_ms = interval;
+        }
+        if let Some(enabled) = self.keyspace_notifications {
+            config.broker.keyspace_notifications = enabled;
+        }
+        if let Some(enabled) = self.configure_keyspace_events {
+            config.broker.configure_keyspace_events = enabled;
+        }
+        if let Some(grace) = self.resume_grace_ms {
+            config.broker.resume_grace_ms = grace;
+        }
+        if let Some(limit) = self.outbound_queue_limit {
+            config.broker.outbound_queue_limit = limit;
+        }
+        if self.jwt_secret.is_some() {
+            config.auth.jwt_secret = self.jwt_secret;
+        }
+        if self.cookie_secret.is_some() {
+            config.auth.cookie_secret = self.cookie_secret;
+        }
+        if self.acl_file.is_some() {
+            config.auth.acl_file = self.acl_file;
+        }
+    }
+}
+
+impl Config {
+    /// Resolve the configuration from the command line, environment and config file
+    pub fn load() -> io::Result<Config> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default()
+        };
+        args.apply(&mut config);
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(
+            |err| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err)
+            )
+        )
+    }
+}
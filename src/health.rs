@@ -0,0 +1,112 @@
+//! Probes for the orchestrator: `/healthz` says whether the process is worth
+//! keeping, `/readyz` whether it can serve clients right now.
+//!
+//! Neither needs credentials.
+
+use std::time::{Duration, Instant};
+
+use actix_web::{rt::time::timeout, web, HttpResponse};
+use serde::Serialize;
+
+use crate::{
+    config::HttpConfig,
+    metrics,
+    server::{
+        RedisHashBroker,
+        query::{Query, QueryError}
+    }
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BrokerState {
+    Running,
+    /// the thread has ended, most likely by panicking
+    Stopped,
+    /// the thread did not answer in time
+    Unresponsive
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RedisState {
+    Up,
+    Down,
+    /// the broker could not be asked
+    Unknown
+}
+
+#[derive(Serialize)]
+struct Health {
+    healthy: bool,
+    broker: BrokerState
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    broker: BrokerState,
+    redis: RedisState,
+    /// messages waiting for the broker thread
+    queue_depth: i64,
+    /// how long the broker took to ping Redis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ping_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>
+}
+
+/// Alive as long as the broker thread is; restart the process otherwise
+pub async fn healthz(srv: web::Data<RedisHashBroker>) -> HttpResponse {
+    if srv.is_running() {
+        HttpResponse::Ok().json(Health { healthy: true, broker: BrokerState::Running })
+    } else {
+        HttpResponse::ServiceUnavailable().json(Health { healthy: false, broker: BrokerState::Stopped })
+    }
+}
+
+/// Ready once the broker gets through its queue to ping Redis within
+/// `http.readiness_timeout_ms`
+pub async fn readyz(
+    srv: web::Data<RedisHashBroker>,
+    http_config: web::Data<HttpConfig>,
+) -> HttpResponse {
+    let mut readiness = Readiness {
+        ready: false,
+        broker: BrokerState::Stopped,
+        redis: RedisState::Unknown,
+        queue_depth: metrics::BROKER_QUEUE.get(),
+        ping_ms: None,
+        error: None
+    };
+    if srv.is_running() {
+        let started = Instant::now();
+        let limit = Duration::from_millis(http_config.readiness_timeout_ms);
+        match timeout(limit, srv.query(Query::Ping)).await {
+            Ok(result) => {
+                readiness.broker = BrokerState::Running;
+                readiness.ping_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+                match result {
+                    Ok(_) => {
+                        readiness.ready = true;
+                        readiness.redis = RedisState::Up;
+                    },
+                    Err(QueryError::Unavailable) => readiness.redis = RedisState::Down,
+                    Err(err) => {
+                        readiness.redis = RedisState::Down;
+                        readiness.error = Some(err.to_string());
+                    }
+                }
+            },
+            Err(_) => {
+                readiness.broker = BrokerState::Unresponsive;
+                readiness.error = Some(format!("no answer from the broker within {limit:?}"));
+            }
+        }
+    }
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
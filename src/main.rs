@@ -1,11 +1,25 @@
-use std::time::Instant;
-
 use actix_files::{Files, NamedFile};
+use std::{
+    future::poll_fn,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant}
+};
+
+use actix::Actor;
 use actix_web::{
-    middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    dev::ServerHandle, http::header, middleware::Logger, rt, web, App, Error, HttpRequest,
+    HttpResponse, HttpServer, Responder,
 };
 use actix_web_actors::ws;
+use tokio::signal::unix::{signal, SignalKind};
 
+mod acl;
+mod api;
+mod auth;
+mod config;
+mod health;
+mod metrics;
 mod server;
 mod session;
 
@@ -13,39 +27,185 @@ async fn index() -> impl Responder {
     NamedFile::open_async("./static/index.html").await.unwrap()
 }
 
-/// Entry point for our websocket route
+/// Turns sessions away once the server is shutting down, telling clients
+/// when to come back
+fn shutting_down(http_config: &config::HttpConfig) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, http_config.retry_after_ms.div_ceil(1000)))
+        .finish()
+}
+
+/// Entry point for our websocket route, upgrading only authenticated requests
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
+    identity: auth::Identity,
     srv: web::Data<server::RedisHashBroker>,
+    http_config: web::Data<config::HttpConfig>,
 ) -> Result<HttpResponse, Error> {
+    if srv.is_stopping() {
+        return Ok(shutting_down(&http_config));
+    }
     ws::start(
-        session::WsChatSession {
-            id: srv.take_next_client_id(),
-            hb: Instant::now(),
-            tx: srv.clone_tx(),
-        },
+        session::WsChatSession::new(
+            srv.take_next_client_id(),
+            srv.clone_tx(),
+            http_config.chunk_size,
+            identity
+        ),
         &req,
         stream,
     )
 }
 
+/// Entry point for server-sent events, `?hash=a&hash=b` naming the hashes to stream
+async fn sse_route(
+    req: HttpRequest,
+    query: web::Query<Vec<(String, String)>>,
+    identity: auth::Identity,
+    srv: web::Data<server::RedisHashBroker>,
+    http_config: web::Data<config::HttpConfig>,
+) -> HttpResponse {
+    if srv.is_stopping() {
+        return shutting_down(&http_config);
+    }
+    let hashes = query.into_inner().into_iter()
+        .filter(|(name, _)| name == "hash")
+        .map(|(_, hash)| hash)
+        .collect();
+    let last_event_id = req.headers().get("Last-Event-ID").and_then(|id| id.to_str().ok());
+    let (session, events) = session::sse::SseSession::new(
+        srv.take_next_client_id(),
+        srv.clone_tx(),
+        hashes,
+        last_event_id,
+        identity
+    );
+    session.start();
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // keeps nginx from holding events back
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events)
+}
+
+/// Wait for SIGTERM or SIGINT, then turn new sessions away, close every
+/// session, stop the broker and finally the server, all before the drain
+/// timeout
+async fn shut_down_on_signal(
+    server: ServerHandle,
+    broker: web::Data<server::RedisHashBroker>,
+    http_config: config::HttpConfig,
+) {
+    let (Ok(mut terminate), Ok(mut interrupt)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt())
+    ) else {
+        log::warn!("cannot listen for SIGTERM, shutting down without draining");
+        return;
+    };
+    poll_fn(|cx| match (terminate.poll_recv(cx), interrupt.poll_recv(cx)) {
+        (Poll::Pending, Poll::Pending) => Poll::Pending,
+        _ => Poll::Ready(())
+    }).await;
+
+    let drain_timeout = Duration::from_millis(http_config.drain_timeout_ms);
+    let deadline = Instant::now() + drain_timeout;
+    log::info!("shutting down, draining for up to {drain_timeout:?}");
+
+    // new sessions are refused from here on, and the open ones close first,
+    // so the server is left with no connections to wait for
+    broker.stop(Duration::from_millis(http_config.retry_after_ms));
+    let joined_broker = broker.clone();
+    let _ = rt::task::spawn_blocking(move || joined_broker.join(deadline)).await;
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let drained = rt::time::timeout(remaining, server.stop(true)).await;
+    if drained.is_err() {
+        log::warn!("connections still open after {drain_timeout:?}, dropping them");
+        server.stop(false).await;
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    log::info!("starting HTTP server at http://0.0.0.0:8080");
+    let config = config::Config::load()?;
+    metrics::register();
+    let http_config = config.http.clone();
+
+    let acl = Arc::new(acl::AccessControl::load(config.auth.acl_file.as_deref())?);
+
+    // one broker for the whole process, every worker shares it
+    let broker = web::Data::new(
+        server::RedisHashBroker::new(&config.redis, &config.broker, acl.clone())
+            .map_err(std::io::Error::other)?
+    );
+    let app_broker = broker.clone();
+    let app_http_config = web::Data::new(http_config.clone());
+    let authenticator = web::Data::new(auth::Authenticator::new(&config.auth));
+    let app_acl = web::Data::from(acl.clone());
+
+    // SIGHUP rereads the access rules
+    let reloading_broker = broker.clone();
+    rt::spawn(async move {
+        let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+            log::warn!("cannot listen for SIGHUP, access rules will not be reloaded");
+            return;
+        };
+        while hangups.recv().await.is_some() {
+            match acl.reload() {
+                Ok(()) => {
+                    log::info!("access rules reloaded");
+                    reloading_broker.access_changed();
+                },
+                Err(err) => log::error!("cannot reload access rules, keeping the old ones: {err}")
+            }
+        }
+    });
 
-    HttpServer::new(move || {
+    log::info!("starting HTTP server at http://{}:{}", http_config.bind, http_config.port);
+
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(server::RedisHashBroker::new()))
+            .app_data(app_broker.clone())
+            .app_data(app_http_config.clone())
+            .app_data(authenticator.clone())
+            .app_data(app_acl.clone())
             .service(web::resource("/").to(index))
             .route("/ws", web::get().to(chat_route))
+            .route("/sse", web::get().to(sse_route))
+            .route("/login", web::post().to(auth::login))
+            .route("/logout", web::post().to(auth::logout))
+            .route("/metrics", web::get().to(metrics::metrics))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .service(web::scope("/api").configure(api::routes))
             .service(Files::new("/static", "./static"))
-            .wrap(Logger::default())
+            // Logger::default() with no token in the request line
+            .wrap(
+                Logger::new(r#"%a "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("request", auth::redacted_request_line)
+            )
     })
-    .workers(2)
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+    .workers(http_config.workers)
+    // shut_down_on_signal handles signals, draining sessions first
+    .disable_signals()
+    // only a backstop, shut_down_on_signal enforces the drain timeout itself
+    .shutdown_timeout(http_config.drain_timeout_ms.div_ceil(1000))
+    .bind((http_config.bind.as_str(), http_config.port))?
+    .run();
+
+    rt::spawn(shut_down_on_signal(server.handle(), broker.clone(), http_config.clone()));
+
+    let result = server.await;
+
+    // a no-op after a signal, but the server may also have stopped by itself
+    log::info!("stopping broker");
+    broker.stop(Duration::from_millis(http_config.retry_after_ms));
+    broker.join(Instant::now() + Duration::from_millis(http_config.drain_timeout_ms));
+
+    result
 }
@@ -0,0 +1,106 @@
+//! What the broker is doing, served at `/metrics` in the Prometheus text format.
+
+use std::sync::LazyLock;
+
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use prometheus::{
+    exponential_buckets,
+    register_histogram,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge,
+    register_int_gauge_vec,
+    Encoder,
+    Histogram,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    TextEncoder
+};
+
+use crate::{
+    auth::{AuthError, Identity},
+    config::HttpConfig
+};
+
+pub static SESSIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "hashboard_sessions",
+    "Sessions the broker holds, by whether they are connected or awaiting resumption",
+    &["state"]
+).unwrap());
+
+pub static HASH_SUBSCRIBERS: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "hashboard_hash_subscribers",
+    "Sessions receiving each key",
+    &["hash"]
+).unwrap());
+
+pub static REDIS_COMMANDS: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "hashboard_redis_commands_total",
+    "Commands sent to Redis by the broker"
+).unwrap());
+
+pub static REDIS_LATENCY: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "hashboard_redis_command_duration_seconds",
+    "Time taken by each round trip to Redis, a pipeline being one",
+    exponential_buckets(0.0001, 2.0, 16).unwrap()
+).unwrap());
+
+pub static REDIS_RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "hashboard_redis_reconnects_total",
+    "Times the connection to Redis was re-established after dropping"
+).unwrap());
+
+pub static UPDATES_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "hashboard_updates_sent_total",
+    "Key updates sent to each session",
+    &["session"]
+).unwrap());
+
+pub static BYTES_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "hashboard_bytes_sent_total",
+    "Bytes of messages sent to each session, before compression",
+    &["session"]
+).unwrap());
+
+pub static HASH_DIFF_FIELDS: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "hashboard_hash_diff_fields",
+    "Fields changed or deleted in each change found to a hash",
+    exponential_buckets(1.0, 4.0, 10).unwrap()
+).unwrap());
+
+pub static BROKER_QUEUE: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "hashboard_broker_queue_depth",
+    "Messages waiting for the broker thread"
+).unwrap());
+
+/// Register every metric up front, so each shows from the start rather
+/// than from when it is first touched
+pub fn register() {
+    LazyLock::force(&SESSIONS);
+    LazyLock::force(&HASH_SUBSCRIBERS);
+    LazyLock::force(&REDIS_COMMANDS);
+    LazyLock::force(&REDIS_LATENCY);
+    LazyLock::force(&REDIS_RECONNECTS);
+    LazyLock::force(&UPDATES_SENT);
+    LazyLock::force(&BYTES_SENT);
+    LazyLock::force(&HASH_DIFF_FIELDS);
+    LazyLock::force(&BROKER_QUEUE);
+}
+
+/// Every metric in the Prometheus text format; as they name the keys being
+/// watched, only for authenticated requests unless `http.metrics_public`
+pub async fn metrics(
+    req: HttpRequest,
+    http_config: web::Data<HttpConfig>,
+) -> Result<HttpResponse, AuthError> {
+    if !http_config.metrics_public {
+        Identity::extract(&req).await?;
+    }
+    let mut body = Vec::new();
+    Ok(match TextEncoder::new().encode(&prometheus::gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string())
+    })
+}
@@ -0,0 +1,1117 @@
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    sync::{mpsc::{Receiver, RecvTimeoutError, TryRecvError}, Arc},
+    time::{Duration, Instant}
+};
+
+use actix::Recipient;
+
+use crate::{
+    acl::{Access, AccessControl},
+    auth::Identity,
+    config::BrokerConfig,
+    metrics,
+    server::{
+        BrokerMessage,
+        SessionMessage,
+        SessionMessages,
+        Shutdown,
+        client::{Client, PatternUpdate, RedisStatus, SessionLink},
+        connection::{is_connection_error, Connection, RedisConnector},
+        field_filter::FieldFilter,
+        keyspace::{KeyspaceEvent, KeyspaceListener},
+        query::{HashQuery, Query, QueryAnswer, QueryError, QueryReply},
+        redis_hash::{HashScan, RedisHashContentsUpdate},
+        redis_key::{RedisKeyContents, RedisKeyUpdate},
+        schedule::{RefreshSchedule, RefreshTarget},
+        snapshot::KeySnapshot,
+        write::{self, WriteError}
+    },
+    session::{
+        client_action::{ClientAction, ClientActions, HashWrite, KeyRequest, RequestId},
+        protocol::Resume
+    }
+};
+
+/// State of the broker thread: the connected clients, which hashes they
+/// requested and when each hash is next read from Redis.
+///
+/// Each key is kept once, as a versioned snapshot; clients only remember
+/// which version they were sent, so every change is worked out once.
+///
+/// Clients may also subscribe to glob patterns, receiving every hash whose
+/// name matches and being told as matching hashes appear and vanish.
+///
+/// With a keyspace listener, hashes are re-read only when Redis reports a
+/// change to them; without one every requested hash is polled.
+///
+/// While Redis is unreachable the broker keeps its clients, their
+/// subscriptions and caches, and resyncs every hash once reconnected.
+pub struct Broker {
+    connector: RedisConnector,
+    listener: Option<KeyspaceListener>,
+    clients: HashMap<usize, Client>,
+    hash_clients: HashMap<String, HashSet<usize>>,
+    /// the one copy of each key clients receive
+    snapshots: HashMap<String, KeySnapshot>,
+    pattern_clients: HashMap<String, HashSet<usize>>,
+    /// hashes last found matching each pattern
+    pattern_keys: HashMap<String, HashSet<String>>,
+    schedule: RefreshSchedule,
+    stream_backlog: usize,
+    hscan_threshold: usize,
+    hscan_page_size: usize,
+    /// large hashes part way through being read
+    scans: HashMap<String, HashScan>,
+    /// large hashes part way through being read for a query, by query id
+    hash_queries: HashMap<usize, HashQuery>,
+    next_query_id: usize,
+    /// client each resume token belongs to
+    resume_tokens: HashMap<String, usize>,
+    /// how long a disconnected client is kept for resuming
+    resume_grace: Duration,
+    /// how many messages may be queued for a session before its updates are held back
+    queue_limit: usize,
+    /// which keys each client may read and write
+    acl: Arc<AccessControl>,
+    /// every open session, whether or not it has a client yet, to close on shutdown
+    sessions: HashMap<usize, Recipient<Shutdown>>
+}
+
+impl Broker {
+    pub fn new(
+        connector: RedisConnector,
+        listener: Option<KeyspaceListener>,
+        config: &BrokerConfig,
+        acl: Arc<AccessControl>
+    ) -> Broker {
+        Broker {
+            connector,
+            listener,
+            clients: HashMap::new(),
+            hash_clients: HashMap::new(),
+            snapshots: HashMap::new(),
+            pattern_clients: HashMap::new(),
+            pattern_keys: HashMap::new(),
+            schedule: RefreshSchedule::new(config),
+            stream_backlog: config.stream_backlog,
+            hscan_threshold: config.hscan_threshold,
+            hscan_page_size: config.hscan_page_size,
+            scans: HashMap::new(),
+            hash_queries: HashMap::new(),
+            next_query_id: 0,
+            resume_tokens: HashMap::new(),
+            resume_grace: Duration::from_millis(config.resume_grace_ms),
+            queue_limit: config.outbound_queue_limit,
+            acl,
+            sessions: HashMap::new()
+        }
+    }
+
+    /// Serve messages until told to stop or every sender has hung up.
+    ///
+    /// Blocks on the channel while idle, waking only when a hash is due for
+    /// refreshing.
+    pub fn run(mut self, rx: Receiver<BrokerMessage>) {
+        loop {
+            let deadline = if self.connector.is_up() {
+                self.schedule.next_deadline()
+            } else {
+                self.connector.reconnect_deadline()
+            };
+            let deadline = [deadline, self.next_detached_expiry(), self.next_flush()]
+                .into_iter()
+                .flatten()
+                .min();
+            let message = match deadline {
+                None => match rx.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break
+                },
+                Some(deadline) => match rx.recv_timeout(
+                    deadline.saturating_duration_since(Instant::now())
+                ) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+            };
+
+            if let Some(mut message) = message {
+                // drain whatever else has queued up before touching Redis
+                loop {
+                    metrics::BROKER_QUEUE.dec();
+                    if let BrokerMessage::Stop { retry_after } = message {
+                        self.shut_down(retry_after);
+                        return;
+                    }
+                    self.handle_message(message);
+                    message = match rx.try_recv() {
+                        Ok(message) => message,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return
+                    };
+                }
+            }
+
+            let now = Instant::now();
+            self.expire_detached(now);
+            self.flush_clients(now);
+            if !self.connector.is_up() {
+                if let Some(deadline) = self.connector.reconnect_deadline() {
+                    if deadline <= now {
+                        self.reconnect();
+                    }
+                }
+                if !self.connector.is_up() {
+                    continue;
+                }
+            }
+            while let Some(target) = self.schedule.pop_due(now) {
+                match target {
+                    RefreshTarget::Hash(hash) => self.refresh_hash(&hash),
+                    RefreshTarget::Pattern(pattern) => self.rescan_pattern(&pattern),
+                    RefreshTarget::Query(id) => self.continue_hash_query(id)
+                }
+                if !self.connector.is_up() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Close every session and stop listening to Redis, before the broker
+    /// is dropped along with its connection
+    fn shut_down(&mut self, retry_after: Duration) {
+        log::info!("closing {} sessions", self.sessions.len());
+        let shutdown = Shutdown { retry_after };
+        for session in self.sessions.values() {
+            session.do_send(shutdown.clone());
+        }
+        if let Some(listener) = self.listener.take() {
+            listener.stop();
+        }
+    }
+
+    fn broadcast_status(&self, status: RedisStatus) {
+        for client in self.clients.values() {
+            client.send_status(status);
+        }
+    }
+
+    fn connection_lost(&mut self, err: &redis::RedisError) {
+        log::error!("lost connection to Redis: {err}");
+        self.connector.disconnect();
+        // restarted once reconnected
+        self.scans.clear();
+        for (id, query) in self.hash_queries.drain() {
+            self.schedule.unschedule(&RefreshTarget::Query(id));
+            let _ = query.reply.send(Err(QueryError::Unavailable));
+        }
+        self.broadcast_status(RedisStatus::RedisDown);
+    }
+
+    /// Find out whether Redis is still reachable
+    fn check_connection(&mut self) {
+        let Some(redis_connection) = self.connector.connection() else {
+            return;
+        };
+        if let Err(err) = redis::cmd("PING").query::<()>(redis_connection) {
+            if is_connection_error(&err) {
+                self.connection_lost(&err);
+            }
+        }
+    }
+
+    fn reconnect(&mut self) {
+        if !self.connector.reconnect() {
+            return;
+        }
+        log::info!("reconnected to Redis");
+        metrics::REDIS_RECONNECTS.inc();
+        self.broadcast_status(RedisStatus::RedisUp);
+
+        // changes made during the outage went unseen
+        self.resync();
+    }
+
+    /// Re-read every hash and rescan every pattern
+    fn resync(&mut self) {
+        for hash in self.hash_clients.keys() {
+            self.schedule.schedule_now(&RefreshTarget::Hash(hash.clone()));
+        }
+        for pattern in self.pattern_clients.keys() {
+            self.schedule.schedule_now(&RefreshTarget::Pattern(pattern.clone()));
+        }
+    }
+
+    fn handle_message(&mut self, message: BrokerMessage) {
+        match message {
+            BrokerMessage::Session(message) => self.handle_session_message(message),
+            BrokerMessage::Keyspace(event) => self.handle_keyspace_event(event),
+            BrokerMessage::AccessChanged => self.recheck_access(),
+            BrokerMessage::Query { query: Query::Hash(hash), reply } => self.query_hash(hash, reply),
+            BrokerMessage::Query { query, reply } => {
+                let result = match self.connector.connection() {
+                    Some(redis_connection) => query.run(redis_connection),
+                    None => Err(QueryError::Unavailable)
+                };
+                if let Err(QueryError::Redis(err)) = &result {
+                    self.handle_redis_error(err, "cannot answer query");
+                }
+                let _ = reply.send(result);
+            },
+            // dealt with in the loop
+            BrokerMessage::Stop { .. } => ()
+        }
+    }
+
+    /// Answer a read of all of `hash`, paging through it with HSCAN like a
+    /// subscription if it has more than `hscan_threshold` fields, so other
+    /// reads go on in between
+    fn query_hash(&mut self, hash: String, reply: QueryReply) {
+        let Some(redis_connection) = self.connector.connection() else {
+            let _ = reply.send(Err(QueryError::Unavailable));
+            return;
+        };
+        let length = if self.hscan_threshold > 0 {
+            redis::cmd("HLEN").arg(&hash).query::<usize>(redis_connection)
+        } else {
+            Ok(0)
+        };
+        let result = match length {
+            Ok(length) if length > self.hscan_threshold => {
+                let id = self.next_query_id;
+                self.next_query_id += 1;
+                self.hash_queries.insert(id, HashQuery { hash, scan: HashScan::new(), reply });
+                self.schedule.schedule_continuation(&RefreshTarget::Query(id));
+                return;
+            },
+            Ok(_) => Query::Hash(hash).run(redis_connection),
+            Err(err) => Err(QueryError::Redis(err))
+        };
+        if let Err(QueryError::Redis(err)) = &result {
+            self.handle_redis_error(err, "cannot answer query");
+        }
+        let _ = reply.send(result);
+    }
+
+    /// Read the next page of the hash query `id` is after, answering it
+    /// once every page is read
+    fn continue_hash_query(&mut self, id: usize) {
+        let target = RefreshTarget::Query(id);
+        let Some(mut query) = self.hash_queries.remove(&id) else {
+            return;
+        };
+        // nobody is waiting for the answer any more
+        if query.reply.is_closed() {
+            self.schedule.unschedule(&target);
+            return;
+        }
+        let Some(redis_connection) = self.connector.connection() else {
+            self.schedule.unschedule(&target);
+            let _ = query.reply.send(Err(QueryError::Unavailable));
+            return;
+        };
+        if let Err(err) = query.scan.next_page(redis_connection, &query.hash, self.hscan_page_size) {
+            self.schedule.unschedule(&target);
+            self.handle_redis_error(&err, "cannot answer query");
+            let _ = query.reply.send(Err(QueryError::Redis(err)));
+            return;
+        }
+        if !query.scan.is_complete() {
+            self.hash_queries.insert(id, query);
+            self.schedule.schedule_continuation(&target);
+            return;
+        }
+        self.schedule.unschedule(&target);
+        // deleted while being read
+        let result = if query.scan.contents.is_empty() {
+            Err(QueryError::MissingKey)
+        } else {
+            Ok(QueryAnswer::Hash(query.scan.contents.into_iter().collect()))
+        };
+        let _ = query.reply.send(result);
+    }
+
+    fn handle_keyspace_event(&mut self, event: KeyspaceEvent) {
+        let key = match event {
+            KeyspaceEvent::Subscribed(key) => key,
+            KeyspaceEvent::PatternSubscribed(pattern) => {
+                if self.pattern_clients.contains_key(&pattern) {
+                    self.schedule.schedule_now(&RefreshTarget::Pattern(pattern));
+                }
+                return;
+            },
+            KeyspaceEvent::Unavailable => {
+                log::warn!("keyspace notifications unavailable, polling hashes instead");
+                self.listener = None;
+                self.resync();
+                return;
+            },
+            KeyspaceEvent::ConnectionLost => {
+                self.check_connection();
+                return;
+            },
+            KeyspaceEvent::Changed { key, event } => {
+                log::debug!("keyspace event {event} on {key}");
+                key
+            },
+            KeyspaceEvent::PatternChanged { pattern, key, event } => {
+                log::debug!("keyspace event {event} on {key}, matching {pattern}");
+                self.handle_pattern_event(&pattern, &key, &event);
+                key
+            }
+        };
+        if let Some(scan) = self.scans.get_mut(&key) {
+            scan.stale = true;
+        }
+        if self.hash_clients.contains_key(&key) {
+            self.schedule.schedule_now(&RefreshTarget::Hash(key));
+        }
+    }
+
+    /// Follow the creation and deletion of keys matching `pattern`
+    fn handle_pattern_event(&mut self, pattern: &str, key: &str, event: &str) {
+        let Some(keys) = self.pattern_keys.get(pattern) else {
+            return;
+        };
+        let known = keys.contains(key);
+        let mut added = HashSet::new();
+        let mut removed = HashSet::new();
+        match event {
+            "del" | "expired" | "evicted" | "rename_from" => if known {
+                removed.insert(key.to_string());
+            },
+            // anything else writes to the key, which therefore exists
+            _ => if !known {
+                added.insert(key.to_string());
+            }
+        }
+        self.update_pattern_keys(pattern, added, removed);
+    }
+
+    fn handle_session_message(&mut self, message: SessionMessage) {
+        let SessionMessage { id, message } = message;
+        match message {
+            SessionMessages::Open { closer } => {
+                self.sessions.insert(id, closer);
+            },
+
+            SessionMessages::Connect { link, identity, resume_token, resume } => {
+                let asked_to_resume = resume.is_some();
+                let unresumed = match resume {
+                    Some(resume) => self.resume_client(id, link, &identity, &resume_token, resume).err(),
+                    None => Some(link)
+                };
+                if let Some(link) = unresumed {
+                    let client = Client::new(id, link, identity, resume_token.clone(), self.queue_limit);
+                    if asked_to_resume {
+                        client.send_resume(false, Vec::new());
+                    }
+                    if let Some(token) = resume_token {
+                        self.resume_tokens.insert(token, id);
+                    }
+                    self.clients.insert(id, client);
+                }
+                if !self.connector.is_up() {
+                    if let Some(client) = self.clients.get(&id) {
+                        client.send_status(RedisStatus::RedisDown);
+                    }
+                }
+                self.count_sessions();
+            },
+
+            SessionMessages::Disconnect => {
+                self.sessions.remove(&id);
+                let Some(client) = self.clients.get_mut(&id) else {
+                    return;
+                };
+                if client.resume_token.is_some() && !self.resume_grace.is_zero() {
+                    client.detached_until = Some(Instant::now() + self.resume_grace);
+                } else {
+                    self.remove_client(id);
+                }
+                self.count_sessions();
+            },
+
+            SessionMessages::Action(ClientAction { action }) => self.handle_action(id, action)
+        }
+    }
+
+    /// Bring the session gauges up to date after clients came or went
+    fn count_sessions(&self) {
+        let detached = self.clients.values().filter(|client| client.detached_until.is_some()).count();
+        metrics::SESSIONS.with_label_values(&["attached"]).set((self.clients.len() - detached) as i64);
+        metrics::SESSIONS.with_label_values(&["detached"]).set(detached as i64);
+    }
+
+    /// Forget client `id` and everything it subscribed to
+    fn remove_client(&mut self, id: usize) {
+        let Some(client) = self.clients.remove(&id) else {
+            return;
+        };
+        if let Some(token) = &client.resume_token {
+            self.resume_tokens.remove(token);
+        }
+        let _ = metrics::UPDATES_SENT.remove_label_values(&[&client.metrics_label]);
+        let _ = metrics::BYTES_SENT.remove_label_values(&[&client.metrics_label]);
+        let hashes: Vec<String> = self.hash_clients.keys().cloned().collect();
+        for hash in hashes {
+            self.remove_hash_client(&hash, id);
+        }
+        let patterns: Vec<String> = self.pattern_clients.keys().cloned().collect();
+        for pattern in patterns {
+            self.remove_pattern_client(&pattern, id);
+        }
+    }
+
+    /// Hand the client `resume` names over to session `id`, giving `link`
+    /// back if there was none to resume. Only the same user may resume it
+    fn resume_client(
+        &mut self,
+        id: usize,
+        link: SessionLink,
+        identity: &Identity,
+        resume_token: &Option<String>,
+        resume: Resume
+    ) -> Result<(), SessionLink> {
+        let resumable = self.resume_tokens.get(&resume.token)
+            .and_then(|old_id| self.clients.get(old_id))
+            .is_some_and(|client| client.identity.user == identity.user);
+        if !resumable {
+            return Err(link);
+        }
+        // the earlier session may not even have noticed its connection dropped
+        let Some(old_id) = self.resume_tokens.remove(&resume.token) else {
+            return Err(link);
+        };
+        let Some(mut client) = self.clients.remove(&old_id) else {
+            return Err(link);
+        };
+        for clients in self.hash_clients.values_mut().chain(self.pattern_clients.values_mut()) {
+            if clients.remove(&old_id) {
+                clients.insert(id);
+            }
+        }
+        client.attach(link, resume_token.clone(), &resume.seqs);
+        if let Some(token) = resume_token {
+            self.resume_tokens.insert(token.clone(), id);
+        }
+
+        let dropped = resume.seqs.keys()
+            .filter(|key| !self.hash_clients.get(*key).is_some_and(|clients| clients.contains(&id)))
+            .cloned()
+            .collect();
+        client.send_resume(true, dropped);
+        // pattern changes made while away went unseen: keys the client last
+        // saw that match a pattern but are no longer among its keys are gone
+        for pattern in &client.patterns {
+            let Some(keys) = self.pattern_keys.get(pattern) else {
+                continue;
+            };
+            let matcher = glob::Pattern::new(pattern).ok();
+            let removed = resume.seqs.keys()
+                .filter(|key| !keys.contains(*key) && matcher.as_ref().is_some_and(|matcher| matcher.matches(key)))
+                .cloned()
+                .collect();
+            client.send_pattern_update(&PatternUpdate {
+                pattern: pattern.clone(),
+                added: self.acl.readable(&client.identity, keys),
+                removed
+            });
+        }
+        self.clients.insert(id, client);
+
+        // catch the client up with anything that changed meanwhile
+        let hashes: Vec<String> = self.hash_clients.iter()
+            .filter(|(_, clients)| clients.contains(&id))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in hashes {
+            self.publish(&hash, None);
+        }
+        Ok(())
+    }
+
+    fn next_detached_expiry(&self) -> Option<Instant> {
+        self.clients.values().filter_map(|client| client.detached_until).min()
+    }
+
+    fn next_flush(&self) -> Option<Instant> {
+        self.clients.values().filter_map(|client| client.next_flush).min()
+    }
+
+    /// Send the updates held back from clients that are due by `now`
+    fn flush_clients(&mut self, now: Instant) {
+        for client in self.clients.values_mut() {
+            if client.next_flush.is_some_and(|next| next <= now) {
+                client.flush(now, &self.snapshots);
+            }
+        }
+    }
+
+    /// Forget the clients whose session went away and was not resumed in time
+    fn expire_detached(&mut self, now: Instant) {
+        let expired: Vec<usize> = self.clients.iter()
+            .filter(|(_, client)| client.detached_until.is_some_and(|until| until <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for id in expired {
+            self.remove_client(id);
+        }
+        self.count_sessions();
+    }
+
+    fn handle_action(&mut self, id: usize, action: ClientActions) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        match action {
+            // answered by the session before it connected
+            ClientActions::Hello(_) => (),
+
+            ClientActions::Request(requests) => {
+                let mut hash_names = Vec::new();
+                for request in requests {
+                    let (hash, filter, interval) = match request {
+                        KeyRequest::Key(hash) => (hash, None, None),
+                        KeyRequest::Subscription(subscription) => {
+                            let checked = subscription.min_interval().and_then(|interval| {
+                                Ok((subscription.fields.map(FieldFilter::new).transpose()?, interval))
+                            });
+                            match checked {
+                                Ok((filter, interval)) => (subscription.key, filter, interval),
+                                Err(reason) => {
+                                    client.send_request_error(&subscription.key, reason);
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    if !self.acl.allows(&client.identity, &hash, Access::Read) {
+                        client.send_forbidden(&hash);
+                        continue;
+                    }
+                    client.set_field_filter(&hash, filter);
+                    client.set_min_interval(&hash, interval);
+                    client.requested.insert(hash.clone());
+                    hash_names.push(hash);
+                }
+
+                for hash in hash_names {
+                    self.add_hash_client(&hash, id);
+                }
+            },
+
+            ClientActions::Drop(hash_names) => {
+                for hash in &hash_names {
+                    client.requested.remove(hash);
+                    client.set_field_filter(hash, None);
+                    client.set_min_interval(hash, None);
+                }
+
+                for hash in hash_names {
+                    self.release_hash(&hash, id);
+                }
+            },
+
+            ClientActions::SubscribePattern(patterns) => {
+                client.patterns.extend(patterns.iter().cloned());
+
+                for pattern in patterns {
+                    self.add_pattern_client(&pattern, id);
+                }
+            },
+
+            ClientActions::UnsubscribePattern(patterns) => {
+                for pattern in &patterns {
+                    client.patterns.remove(pattern);
+                }
+
+                for pattern in patterns {
+                    self.remove_pattern_client(&pattern, id);
+                }
+            },
+
+            ClientActions::Hset(HashWrite { id: request_id, hash, fields, expect }) => {
+                self.write_hash(id, request_id, &hash, |redis_connection| {
+                    write::hset(redis_connection, &hash, &fields, expect.as_ref())
+                });
+            },
+
+            ClientActions::Hdel(HashWrite { id: request_id, hash, fields, expect }) => {
+                self.write_hash(id, request_id, &hash, |redis_connection| {
+                    write::hdel(redis_connection, &hash, &fields, expect.as_ref())
+                });
+            },
+
+            ClientActions::Hincrby(HashWrite { id: request_id, hash, fields, expect }) => {
+                self.write_hash(id, request_id, &hash, |redis_connection| {
+                    write::hincrby(redis_connection, &hash, &fields, expect.as_ref())
+                });
+            }
+        }
+    }
+
+    /// Run a client's write to `hash`, answering with an ack or an error.
+    ///
+    /// Subscribers, the writer included, see the result as a normal update.
+    fn write_hash(
+        &mut self,
+        id: usize,
+        request_id: RequestId,
+        hash: &str,
+        write: impl FnOnce(&mut Connection) -> Result<(), WriteError>
+    ) {
+        let allowed = self.clients.get(&id).is_some_and(
+            |client| self.acl.allows(&client.identity, hash, Access::Write)
+        );
+        let result = match self.connector.connection() {
+            _ if !allowed => Err(WriteError::Forbidden),
+            Some(redis_connection) => write(redis_connection),
+            None => Err(WriteError::Unavailable)
+        };
+        if let Err(WriteError::Redis(err)) = &result {
+            self.handle_redis_error(err, &format!("cannot write to {hash}"));
+        }
+
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                client.send_ack(request_id);
+                if self.listener.is_none() && self.hash_clients.contains_key(hash) {
+                    self.schedule.schedule_now(&RefreshTarget::Hash(hash.to_string()));
+                }
+            },
+            Err(err) => client.send_error(request_id, err)
+        }
+    }
+
+    /// Start sending `hash` to client `id`
+    fn add_hash_client(&mut self, hash: &str, id: usize) {
+        if !self.hash_clients.contains_key(hash) {
+            if let Some(listener) = &self.listener {
+                listener.subscribe(hash);
+            }
+        }
+
+        // file client's hash-requests
+        let hash_clients = self.hash_clients.entry(hash.to_string()).or_default();
+        hash_clients.insert(id);
+        metrics::HASH_SUBSCRIBERS.with_label_values(&[hash]).set(hash_clients.len() as i64);
+
+        // what is already known needn't wait for the next read, unless it
+        // lacks fields the client wants, which that read fetches first
+        let wanted = self.wanted_fields(hash);
+        if self.snapshots.get(hash).is_some_and(|snapshot| snapshot.has_fields(wanted.as_ref())) {
+            self.publish(hash, None);
+        }
+        self.schedule.schedule_now(&RefreshTarget::Hash(hash.to_string()));
+    }
+
+    /// Stop sending `hash` to client `id`, forgetting the hash once nobody watches it
+    fn remove_hash_client(&mut self, hash: &str, id: usize) {
+        let Some(hash_clients) = self.hash_clients.get_mut(hash) else {
+            return;
+        };
+        if !hash_clients.remove(&id) {
+            return;
+        }
+        // remove from running list
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.handle_drop(hash);
+        }
+        metrics::HASH_SUBSCRIBERS.with_label_values(&[hash]).set(hash_clients.len() as i64);
+        if hash_clients.is_empty() {
+            let _ = metrics::HASH_SUBSCRIBERS.remove_label_values(&[hash]);
+            self.hash_clients.remove(hash);
+            self.snapshots.remove(hash);
+            self.scans.remove(hash);
+            self.schedule.unschedule(&RefreshTarget::Hash(hash.to_string()));
+            if let Some(listener) = &self.listener {
+                listener.unsubscribe(hash);
+            }
+        }
+    }
+
+    /// Whether client `id` asked for `hash`, by name or through a pattern
+    fn client_wants(&self, id: usize, hash: &str) -> bool {
+        let Some(client) = self.clients.get(&id) else {
+            return false;
+        };
+        client.requested.contains(hash) || client.patterns.iter().any(
+            |pattern| self.pattern_keys.get(pattern).is_some_and(|keys| keys.contains(hash))
+        )
+    }
+
+    /// Stop sending `hash` to client `id` unless it still wants it some other way
+    fn release_hash(&mut self, hash: &str, id: usize) {
+        if !self.client_wants(id, hash) {
+            self.remove_hash_client(hash, id);
+        }
+    }
+
+    fn add_pattern_client(&mut self, pattern: &str, id: usize) {
+        let pattern_clients = self.pattern_clients.entry(pattern.to_string()).or_default();
+        if !pattern_clients.insert(id) {
+            return;
+        }
+
+        match self.pattern_keys.get(pattern) {
+            Some(keys) => {
+                // already being followed, catch the client up
+                let Some(client) = self.clients.get(&id) else {
+                    return;
+                };
+                let keys = self.acl.readable(&client.identity, keys);
+                client.send_pattern_update(&PatternUpdate {
+                    pattern: pattern.to_string(),
+                    added: keys.clone(),
+                    removed: HashSet::new()
+                });
+                for key in keys {
+                    self.add_hash_client(&key, id);
+                }
+            },
+            None => {
+                self.pattern_keys.insert(pattern.to_string(), HashSet::new());
+                if let Some(listener) = &self.listener {
+                    listener.psubscribe(pattern);
+                }
+                self.schedule.schedule_now(&RefreshTarget::Pattern(pattern.to_string()));
+            }
+        }
+    }
+
+    /// Hold existing subscriptions to changed access rules: drop the keys
+    /// clients may no longer read, and add pattern matches they now may
+    fn recheck_access(&mut self) {
+        let pattern_subscriptions: Vec<(String, usize)> = self.pattern_clients.iter()
+            .flat_map(|(pattern, ids)| ids.iter().map(move |id| (pattern.clone(), *id)))
+            .collect();
+        for (pattern, id) in pattern_subscriptions {
+            let (Some(client), Some(keys)) = (self.clients.get(&id), self.pattern_keys.get(&pattern)) else {
+                continue;
+            };
+            let mut update = PatternUpdate {
+                pattern,
+                added: HashSet::new(),
+                removed: HashSet::new()
+            };
+            for key in keys {
+                let receives = self.hash_clients.get(key).is_some_and(|ids| ids.contains(&id));
+                match (self.acl.allows(&client.identity, key, Access::Read), receives) {
+                    (true, false) => update.added.insert(key.clone()),
+                    (false, true) => update.removed.insert(key.clone()),
+                    _ => false
+                };
+            }
+            if update.added.is_empty() && update.removed.is_empty() {
+                continue;
+            }
+            client.send_pattern_update(&update);
+            for key in &update.added {
+                self.add_hash_client(key, id);
+            }
+        }
+
+        let hash_subscriptions: Vec<(String, usize)> = self.hash_clients.iter()
+            .flat_map(|(hash, ids)| ids.iter().map(move |id| (hash.clone(), *id)))
+            .collect();
+        for (hash, id) in hash_subscriptions {
+            let Some(client) = self.clients.get_mut(&id) else {
+                continue;
+            };
+            if self.acl.allows(&client.identity, &hash, Access::Read) {
+                continue;
+            }
+            if client.requested.remove(&hash) {
+                client.send_forbidden(&hash);
+            }
+            self.remove_hash_client(&hash, id);
+        }
+    }
+
+    fn remove_pattern_client(&mut self, pattern: &str, id: usize) {
+        let Some(pattern_clients) = self.pattern_clients.get_mut(pattern) else {
+            return;
+        };
+        if !pattern_clients.remove(&id) {
+            return;
+        }
+        let emptied = pattern_clients.is_empty();
+
+        let keys = if emptied {
+            self.pattern_clients.remove(pattern);
+            self.schedule.unschedule(&RefreshTarget::Pattern(pattern.to_string()));
+            if let Some(listener) = &self.listener {
+                listener.punsubscribe(pattern);
+            }
+            self.pattern_keys.remove(pattern).unwrap_or_default()
+        } else {
+            self.pattern_keys.get(pattern).cloned().unwrap_or_default()
+        };
+        for key in keys {
+            self.release_hash(&key, id);
+        }
+    }
+
+    /// Record hashes newly matching or no longer matching `pattern`, telling its clients
+    fn update_pattern_keys(
+        &mut self,
+        pattern: &str,
+        added: HashSet<String>,
+        removed: HashSet<String>
+    ) {
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        let Some(keys) = self.pattern_keys.get_mut(pattern) else {
+            return;
+        };
+        keys.extend(added.iter().cloned());
+        for key in &removed {
+            keys.remove(key);
+        }
+
+        let pattern_clients: Vec<usize> = self.pattern_clients.get(pattern)
+            .map(|clients| clients.iter().copied().collect())
+            .unwrap_or_default();
+        for id in pattern_clients {
+            let Some(client) = self.clients.get(&id) else {
+                continue;
+            };
+            // keys the client may not read go unmentioned
+            let update = PatternUpdate {
+                pattern: pattern.to_string(),
+                added: self.acl.readable(&client.identity, &added),
+                removed: self.acl.readable(&client.identity, &removed)
+            };
+            if !update.added.is_empty() || !update.removed.is_empty() {
+                client.send_pattern_update(&update);
+            }
+            for key in &update.added {
+                self.add_hash_client(key, id);
+            }
+            for key in &update.removed {
+                self.release_hash(key, id);
+            }
+        }
+    }
+
+    /// Deal with a failed Redis command, `true` if it was the connection that failed
+    fn handle_redis_error(&mut self, err: &redis::RedisError, context: &str) -> bool {
+        if is_connection_error(err) {
+            // resynced once reconnected
+            self.connection_lost(err);
+            true
+        } else {
+            log::warn!("{context}: {err}");
+            false
+        }
+    }
+
+    /// Tell `clients` that `key` (or a pattern) could not be read
+    fn report_read_error(&self, clients: Option<&HashSet<usize>>, key: &str, err: &redis::RedisError) {
+        for id in clients.into_iter().flatten() {
+            if let Some(client) = self.clients.get(id) {
+                client.send_read_error(key, err);
+            }
+        }
+    }
+
+    /// Find every key matching `pattern` with SCAN
+    fn rescan_pattern(&mut self, pattern: &str) {
+        let Some(redis_connection) = self.connector.connection() else {
+            return;
+        };
+
+        let mut found = HashSet::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let result: redis::RedisResult<(u64, Vec<String>)> = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(1000)
+                .query(redis_connection);
+            match result {
+                Ok((next, keys)) => {
+                    found.extend(keys);
+                    cursor = next;
+                    if cursor == 0 {
+                        break;
+                    }
+                },
+                Err(err) => {
+                    if !self.handle_redis_error(&err, &format!("cannot scan for {pattern}")) {
+                        self.report_read_error(self.pattern_clients.get(pattern), pattern, &err);
+                        self.schedule_next(&RefreshTarget::Pattern(pattern.to_string()));
+                    }
+                    return;
+                }
+            }
+        }
+
+        let Some(keys) = self.pattern_keys.get(pattern) else {
+            return;
+        };
+        let added = found.difference(keys).cloned().collect();
+        let removed = keys.difference(&found).cloned().collect();
+        self.update_pattern_keys(pattern, added, removed);
+
+        self.schedule_next(&RefreshTarget::Pattern(pattern.to_string()));
+    }
+
+    /// Poll `target` again later, unless keyspace events announce its changes
+    fn schedule_next(&mut self, target: &RefreshTarget) {
+        if self.listener.is_none() {
+            self.schedule.schedule_next(target);
+        }
+    }
+
+    /// Bring every client receiving `key` up to the key's snapshot,
+    /// `update` being the change from the version before
+    fn publish(&mut self, key: &str, update: Option<RedisKeyUpdate>) {
+        let (Some(snapshot), Some(hash_clients)) = (self.snapshots.get(key), self.hash_clients.get(key)) else {
+            return;
+        };
+        let whole = OnceCell::new();
+        for clientid in hash_clients {
+            if let Some(client) = self.clients.get_mut(clientid) {
+                client.update_hash(key, snapshot, update.as_ref(), &whole);
+            }
+        }
+    }
+
+    /// The fields of `hash` its clients want, `None` if any wants them all
+    fn wanted_fields(&self, hash: &str) -> Option<HashSet<String>> {
+        let mut wanted = HashSet::new();
+        for id in self.hash_clients.get(hash)? {
+            if let Some(client) = self.clients.get(id) {
+                wanted.extend(client.wanted_fields(hash)?.iter().cloned());
+            }
+        }
+        Some(wanted)
+    }
+
+    /// Read `hash`, whatever type of key it is, and pass it to each client
+    /// that requested it
+    fn refresh_hash(&mut self, hash: &str) {
+        if self.scans.contains_key(hash) {
+            self.scan_hash(hash);
+            return;
+        }
+        if !self.hash_clients.contains_key(hash) {
+            return;
+        }
+        let fields = self.wanted_fields(hash);
+        let Some(redis_connection) = self.connector.connection() else {
+            return;
+        };
+
+        let contents = match RedisKeyContents::read(
+            redis_connection,
+            hash,
+            self.stream_backlog,
+            self.hscan_threshold,
+            fields.as_ref()
+        ) {
+            Ok(Some(contents)) => contents,
+            Ok(None) => {
+                self.scans.insert(hash.to_string(), HashScan::new());
+                self.scan_hash(hash);
+                return;
+            },
+            Err(err) => {
+                if !self.handle_redis_error(&err, &format!("cannot read {hash}")) {
+                    self.report_read_error(self.hash_clients.get(hash), hash, &err);
+                    self.schedule_next(&RefreshTarget::Hash(hash.to_string()));
+                }
+                return;
+            }
+        };
+
+        let update = match self.snapshots.get_mut(hash) {
+            Some(snapshot) => {
+                let update = snapshot.replace(hash, contents);
+                snapshot.fields = fields;
+                update
+            },
+            None => {
+                let mut snapshot = KeySnapshot::new(contents);
+                snapshot.fields = fields;
+                self.snapshots.insert(hash.to_string(), snapshot);
+                None
+            }
+        };
+        self.publish(hash, update);
+
+        self.schedule_next(&RefreshTarget::Hash(hash.to_string()));
+    }
+
+    /// Read the next page of a large hash, passing what changed on to each
+    /// client that requested it as it comes in.
+    ///
+    /// Pages are read one refresh at a time, each counted against the
+    /// request budget, so other hashes are refreshed in between.
+    fn scan_hash(&mut self, hash: &str) {
+        let target = RefreshTarget::Hash(hash.to_string());
+        if !self.hash_clients.contains_key(hash) {
+            return;
+        }
+        let (Some(scan), Some(redis_connection)) = (self.scans.get_mut(hash), self.connector.connection()) else {
+            return;
+        };
+
+        let page = match scan.next_page(redis_connection, hash, self.hscan_page_size) {
+            Ok(page) => page,
+            Err(err) => {
+                self.scans.remove(hash);
+                if !self.handle_redis_error(&err, &format!("cannot read {hash}")) {
+                    self.report_read_error(self.hash_clients.get(hash), hash, &err);
+                    self.schedule_next(&target);
+                }
+                return;
+            }
+        };
+        let complete = scan.is_complete();
+        let snapshot = self.snapshots.entry(hash.to_string())
+            .or_insert_with(|| KeySnapshot::new(RedisKeyContents::None));
+        let update = snapshot.patch_hash(
+            hash,
+            |fields| RedisHashContentsUpdate::from_page(hash, &page, fields)
+        );
+        self.publish(hash, update);
+        if !complete {
+            self.schedule.schedule_continuation(&target);
+            return;
+        }
+
+        let Some(scan) = self.scans.remove(hash) else {
+            return;
+        };
+        if let Some(snapshot) = self.snapshots.get_mut(hash) {
+            // deletions only show once every field was read
+            let update = snapshot.patch_hash(
+                hash,
+                |fields| RedisHashContentsUpdate::from(hash, &scan.contents, Some(fields))
+            );
+            self.publish(hash, update);
+        }
+        if scan.stale {
+            // changed while being read, earlier pages may have missed it
+            self.schedule.schedule_now(&target);
+        } else {
+            self.schedule_next(&target);
+        }
+    }
+}
@@ -1,64 +1,547 @@
-use crate::server::redis_hash::{RedisHash, RedisHashContents};
+use crate::{
+    auth::Identity,
+    metrics,
+    server::{
+        connection::is_connection_error,
+        field_filter::FieldFilter,
+        redis_key::RedisKeyUpdate,
+        snapshot::KeySnapshot,
+        write::WriteError
+    },
+    session::{
+        client_action::{Expectation, RequestId},
+        protocol::{Encoding, LEGACY_PROTOCOL_VERSION, Welcome}
+    }
+};
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::{Duration, Instant}
+};
 use actix::prelude::*;
 use serde::Serialize;
 
-use super::redis_hash::RedisHashContentsUpdate;
-
+/// A message serialised for a session, sent as a text or binary frame
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
-pub struct JsonMessage {
-    pub string: String
+pub enum EncodedMessage {
+    Text(String),
+    Binary(Vec<u8>)
 }
 
-impl JsonMessage {
-    pub fn from(serialisable: impl Serialize) -> JsonMessage {
-        JsonMessage {
-            string: serde_json::to_string(
-                &serialisable
-            ).unwrap()
+impl EncodedMessage {
+    pub fn from(serialisable: impl Serialize, encoding: Encoding) -> EncodedMessage {
+        match encoding {
+            Encoding::Json => EncodedMessage::Text(
+                serde_json::to_string(&serialisable).unwrap()
+            ),
+            // named, so structs become maps rather than arrays
+            Encoding::Msgpack => EncodedMessage::Binary(
+                rmp_serde::to_vec_named(&serialisable).unwrap()
+            ),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(&serialisable, &mut bytes).unwrap();
+                EncodedMessage::Binary(bytes)
+            }
+        }
+    }
+
+    /// `message` in the shape and encoding clients speaking `protocol` expect
+    pub fn encode(message: &ServerMessage, protocol: u32, encoding: Encoding) -> EncodedMessage {
+        if protocol > LEGACY_PROTOCOL_VERSION {
+            return EncodedMessage::from(message, encoding);
+        }
+
+        // protocol 1 predates the envelope
+        let mut value = serde_json::to_value(message).unwrap();
+        if let Some(fields) = value.as_object_mut() {
+            match fields.remove("type").as_ref().and_then(|tag| tag.as_str()) {
+                Some("ack") => {
+                    let id = fields.remove("id");
+                    fields.insert("ack".to_string(), id.unwrap_or_default());
+                },
+                Some("error") => {
+                    fields.remove("code");
+                    let message = fields.remove("message");
+                    fields.insert("error".to_string(), message.unwrap_or_default());
+                },
+                _ => ()
+            }
+        }
+        EncodedMessage::from(value, encoding)
+    }
+
+    /// Size of the frame's payload in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            EncodedMessage::Text(text) => text.len(),
+            EncodedMessage::Binary(bytes) => bytes.len()
         }
     }
 }
 
+/// Whether the broker can currently reach Redis
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RedisStatus {
+    RedisDown,
+    RedisUp
+}
+
+/// Keys that started or stopped matching a client's pattern subscription
+#[derive(Serialize, Clone)]
+pub struct PatternUpdate {
+    pub pattern: String,
+    pub added: HashSet<String>,
+    pub removed: HashSet<String>
+}
+
+/// What went wrong, for clients to act on without parsing messages
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// the client's message is not a valid action
+    InvalidAction,
+    /// the client's hello asks for a protocol or encoding the server lacks
+    UnsupportedProtocol,
+    /// the request carries no valid credentials
+    Unauthorized,
+    /// the user may not read, or write, the key
+    Forbidden,
+    /// the action is well-formed but can't be carried out as asked
+    InvalidRequest,
+    /// a write's expected field values no longer hold
+    Conflict,
+    /// the hash kept changing under a write, which may be retried
+    Contended,
+    /// the key does not exist
+    MissingKey,
+    /// the hash has no such field
+    MissingField,
+    /// the key holds a type the operation does not apply to
+    WrongType,
+    /// Redis is unreachable
+    RedisUnavailable,
+    /// Redis refused a command
+    RedisError,
+    /// the server failed in a way it should not have
+    InternalError
+}
+
+impl ErrorCode {
+    pub fn of_redis_error(err: &redis::RedisError) -> ErrorCode {
+        if is_connection_error(err) {
+            ErrorCode::RedisUnavailable
+        } else if err.code() == Some("WRONGTYPE") {
+            ErrorCode::WrongType
+        } else {
+            ErrorCode::RedisError
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+    /// the write action that failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    /// the key, or pattern, that could not be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// on a conflict, what the expected fields hold now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<Expectation>
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: String) -> ErrorMessage {
+        ErrorMessage {
+            code,
+            message,
+            id: None,
+            key: None,
+            current: None
+        }
+    }
+}
+
+/// Everything sent to clients, tagged with its `type`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Update {
+        /// counts the updates of the key sent to this client
+        seq: u64,
+        /// the update holds all of the key, replacing what the client has
+        whole: bool,
+        #[serde(flatten)]
+        update: &'a RedisKeyUpdate
+    },
+    Pattern(PatternUpdate),
+    Status { status: RedisStatus },
+    /// a write action was carried out
+    Ack { id: RequestId },
+    Error(ErrorMessage),
+    /// answer to a client's hello
+    Welcome(Welcome),
+    /// whether the session asked to resume took over its subscriptions
+    Resume {
+        resumed: bool,
+        /// keys the client last saw that it no longer receives
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        dropped: Vec<String>
+    }
+}
+
+/// How long to wait before trying a backed up session again
+const BACKED_UP_RECHECK: Duration = Duration::from_millis(100);
+
+/// The session a client's messages go to, and how they are put
+pub struct SessionLink {
+    pub session: Recipient<EncodedMessage>,
+    /// protocol version the session negotiated
+    pub protocol: u32,
+    /// encoding the session negotiated
+    pub encoding: Encoding,
+    /// messages sent to the session it has yet to handle
+    pub queued: Arc<AtomicUsize>
+}
+
+/// Changes to a key held back from a client, to be sent as one
+enum Pending {
+    Changes(RedisKeyUpdate),
+    /// the changes can't be merged, the key is sent whole instead
+    Whole
+}
+
+/// What a client was last sent of a key
+struct KeyState {
+    /// version of the broker's snapshot, including what is pending
+    version: u64,
+    pending: Option<Pending>,
+    sent_at: Option<Instant>
+}
+
 pub struct Client {
-    hash_caches: HashMap<String, RedisHashContents>,
-    session: Recipient<JsonMessage>
+    /// what was last sent of each key, whatever its type
+    key_states: HashMap<String, KeyState>,
+    /// `seq` of the last update sent of each key, kept when its state is
+    /// forgotten so a resuming session never mistakes one update for another
+    seqs: HashMap<String, u64>,
+    link: SessionLink,
+    /// labels the client's metrics, the id of the session that opened it
+    pub metrics_label: String,
+    /// who the client authenticated as
+    pub identity: Identity,
+    /// beyond this many messages queued for the session, updates are held back
+    queue_limit: usize,
+    /// lets a later session take over this client
+    pub resume_token: Option<String>,
+    /// set once the session has gone, the client is forgotten after this
+    pub detached_until: Option<Instant>,
+    /// hashes requested by name
+    pub requested: HashSet<String>,
+    /// the fields wanted of hashes requested with some
+    field_filters: HashMap<String, FieldFilter>,
+    /// shortest time between updates of keys requested with one
+    min_intervals: HashMap<String, Duration>,
+    /// when held back updates are next due to be sent
+    pub next_flush: Option<Instant>,
+    /// glob patterns subscribed to
+    pub patterns: HashSet<String>
 }
 
 impl Client {
 	pub fn new(
-		session: Recipient<JsonMessage>
+		id: usize,
+		link: SessionLink,
+		identity: Identity,
+		resume_token: Option<String>,
+		queue_limit: usize
 	) -> Client {
 		Client {
-			hash_caches: HashMap::new(),
-			session
+			key_states: HashMap::new(),
+			seqs: HashMap::new(),
+			link,
+			metrics_label: id.to_string(),
+			identity,
+			queue_limit,
+			resume_token,
+			detached_until: None,
+			requested: HashSet::new(),
+			field_filters: HashMap::new(),
+			min_intervals: HashMap::new(),
+			next_flush: None,
+			patterns: HashSet::new()
 		}
 	}
 
-    pub fn handle_drop(&mut self, hashname: &String) {
-        self.hash_caches.remove(hashname);
-    }
-
-	pub fn update_hash(&mut self, hash: &RedisHash) -> bool {
-        let previous_content = self.hash_caches.insert(
-            hash.name.clone(),
-            hash.contents.clone()
-        );
-        match RedisHashContentsUpdate::from(
-            &hash,
-            &previous_content
-        ) {
-            None => {
-                return false;
-            }
-            Some(update) => {
-                self.session.do_send(
-                    JsonMessage::from(update)
-                );
+    fn send(&self, message: ServerMessage) {
+        let message = EncodedMessage::encode(&message, self.link.protocol, self.link.encoding);
+        metrics::BYTES_SENT.with_label_values(&[&self.metrics_label]).inc_by(message.len() as u64);
+        self.link.queued.fetch_add(1, Ordering::Relaxed);
+        self.link.session.do_send(message);
+    }
+
+    pub fn send_status(&self, status: RedisStatus) {
+        self.send(ServerMessage::Status { status });
+    }
+
+    pub fn send_pattern_update(&self, update: &PatternUpdate) {
+        self.send(ServerMessage::Pattern(update.clone()));
+    }
+
+    pub fn send_ack(&self, id: RequestId) {
+        self.send(ServerMessage::Ack { id });
+    }
+
+    pub fn send_error(&self, id: RequestId, error: WriteError) {
+        let code = match &error {
+            WriteError::Invalid(_) => ErrorCode::InvalidRequest,
+            WriteError::Conflict(_) => ErrorCode::Conflict,
+            WriteError::MissingKey => ErrorCode::MissingKey,
+            WriteError::Forbidden => ErrorCode::Forbidden,
+            WriteError::Contended => ErrorCode::Contended,
+            WriteError::Unavailable => ErrorCode::RedisUnavailable,
+            WriteError::Redis(err) => ErrorCode::of_redis_error(err)
+        };
+        let mut message = ErrorMessage::new(code, error.to_string());
+        message.id = Some(id);
+        if let WriteError::Conflict(current) = error {
+            message.current = Some(current);
+        }
+        self.send(ServerMessage::Error(message));
+    }
+
+    /// Tell the client `key`, or a pattern, could not be read
+    pub fn send_read_error(&self, key: &str, err: &redis::RedisError) {
+        let mut message = ErrorMessage::new(ErrorCode::of_redis_error(err), err.to_string());
+        message.key = Some(key.to_string());
+        self.send(ServerMessage::Error(message));
+    }
+
+    /// Tell the client its request for `key` could not be taken up
+    pub fn send_request_error(&self, key: &str, reason: String) {
+        let mut message = ErrorMessage::new(ErrorCode::InvalidRequest, reason);
+        message.key = Some(key.to_string());
+        self.send(ServerMessage::Error(message));
+    }
+
+    /// Tell the client it may not read `key`
+    pub fn send_forbidden(&self, key: &str) {
+        let mut message = ErrorMessage::new(ErrorCode::Forbidden, "not allowed to read this key".to_string());
+        message.key = Some(key.to_string());
+        self.send(ServerMessage::Error(message));
+    }
+
+    /// Hand the client to a resuming session, which last saw `seqs` of
+    /// each key; keys it saw less of are sent to it whole again
+    pub fn attach(
+        &mut self,
+        link: SessionLink,
+        resume_token: Option<String>,
+        seqs: &HashMap<String, u64>
+    ) {
+        self.link = link;
+        self.resume_token = resume_token;
+        self.detached_until = None;
+        self.key_states.retain(|key, _| seqs.get(key).is_some_and(|seq| self.seqs.get(key) == Some(seq)));
+        // anything held back while detached is now due
+        self.next_flush = Some(Instant::now());
+    }
+
+    pub fn send_resume(&self, resumed: bool, dropped: Vec<String>) {
+        self.send(ServerMessage::Resume { resumed, dropped });
+    }
+
+    /// Limit the fields of `key` sent to those `filter` lets through, or
+    /// with `None` send them all. The key is sent whole again if that
+    /// changes what the client receives
+    pub fn set_field_filter(&mut self, key: &str, filter: Option<FieldFilter>) {
+        if self.field_filters.get(key) == filter.as_ref() {
+            return;
+        }
+        match filter {
+            Some(filter) => self.field_filters.insert(key.to_string(), filter),
+            None => self.field_filters.remove(key)
+        };
+        self.key_states.remove(key);
+    }
+
+    /// The fields of `key` to read for the client, `None` for all of them
+    pub fn wanted_fields(&self, key: &str) -> Option<&HashSet<String>> {
+        self.field_filters.get(key)?.names()
+    }
+
+    /// Send `key` no more often than every `interval`, or with `None` as
+    /// often as it changes
+    pub fn set_min_interval(&mut self, key: &str, interval: Option<Duration>) {
+        match interval {
+            Some(interval) => self.min_intervals.insert(key.to_string(), interval),
+            None => self.min_intervals.remove(key)
+        };
+    }
+
+    /// Whether the session has fallen too far behind to be sent more
+    fn is_backed_up(&self) -> bool {
+        self.queue_limit > 0 && self.link.queued.load(Ordering::Relaxed) > self.queue_limit
+    }
+
+    /// When `key` may next be sent, given `state`, what was last sent of it
+    fn due_at(&self, key: &str, state: &KeyState, now: Instant) -> Instant {
+        match (state.sent_at, self.min_intervals.get(key)) {
+            (Some(sent_at), Some(interval)) => sent_at.checked_add(*interval).map_or(now, |due| due.max(now)),
+            _ => now
+        }
+    }
+
+    pub fn handle_drop(&mut self, hashname: &str) {
+        self.key_states.remove(hashname);
+        self.min_intervals.remove(hashname);
+    }
+
+    /// Bring the client up to `snapshot` of key `name`: with `update` if it
+    /// holds the version before, otherwise with the whole key, which
+    /// `whole` works out once for every client needing it. Held back, and
+    /// merged with what came before, while the key's minimum interval has
+    /// yet to pass, the session is backed up or there is no session
+	pub fn update_hash(
+        &mut self,
+        name: &str,
+        snapshot: &KeySnapshot,
+        update: Option<&RedisKeyUpdate>,
+        whole: &OnceCell<Option<RedisKeyUpdate>>
+    ) -> bool {
+        let update = match (self.key_states.get(name), update) {
+            (Some(state), _) if state.version == snapshot.version => return false,
+            (Some(state), Some(update)) if state.version + 1 == snapshot.version => Some(update),
+            _ => None
+        };
+
+        let now = Instant::now();
+        let held_until = match self.key_states.get(name) {
+            // merged until a session resumes the client, which then gets them as one
+            _ if self.detached_until.is_some() => self.detached_until,
+            _ if self.is_backed_up() => Some(now + BACKED_UP_RECHECK),
+            Some(state) if state.pending.is_some() || self.due_at(name, state, now) > now => {
+                Some(self.due_at(name, state, now))
+            },
+            _ => None
+        };
+        if let Some(due) = held_until {
+            let state = self.key_states.entry(name.to_string()).or_insert(KeyState {
+                version: 0,
+                pending: None,
+                sent_at: None
+            });
+            state.pending = match (state.pending.take(), update) {
+                (None, Some(update)) => Some(Pending::Changes(update.clone())),
+                (Some(Pending::Changes(mut pending)), Some(update)) => {
+                    // changes that can't be merged are sent as the whole key
+                    if pending.merge(update) {
+                        Some(Pending::Changes(pending))
+                    } else {
+                        Some(Pending::Whole)
+                    }
+                },
+                _ => Some(Pending::Whole)
+            };
+            state.version = snapshot.version;
+            self.next_flush = Some(self.next_flush.map_or(due, |next| next.min(due)));
+            return false;
+        }
+
+        match update {
+            Some(update) => self.deliver(name, snapshot.version, update, false, now),
+            None => match whole.get_or_init(|| snapshot.whole(name)) {
+                Some(whole) => self.deliver(name, snapshot.version, whole, true, now),
+                None => false
             }
         }
-        return true;
 	}
-}
\ No newline at end of file
+
+    /// Send the changes held back from the client that are due by `now`,
+    /// the whole keys taken from `snapshots`
+    pub fn flush(&mut self, now: Instant, snapshots: &HashMap<String, KeySnapshot>) {
+        self.next_flush = None;
+        if self.detached_until.is_some() {
+            return;
+        }
+        if self.is_backed_up() {
+            self.next_flush = Some(now + BACKED_UP_RECHECK);
+            return;
+        }
+
+        let due: Vec<String> = self.key_states.iter()
+            .filter(|(key, state)| state.pending.is_some() && self.due_at(key, state, now) <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in due {
+            let Some(state) = self.key_states.get_mut(&key) else { continue };
+            let version = state.version;
+            match state.pending.take() {
+                Some(Pending::Changes(update)) => {
+                    self.deliver(&key, version, &update, false, now);
+                },
+                Some(Pending::Whole) => {
+                    let whole = snapshots.get(&key).and_then(
+                        |snapshot| snapshot.whole(&key).map(|whole| (snapshot.version, whole))
+                    );
+                    if let Some((version, whole)) = whole {
+                        self.deliver(&key, version, &whole, true, now);
+                    }
+                },
+                None => ()
+            }
+        }
+
+        self.next_flush = self.key_states.iter()
+            .filter(|(_, state)| state.pending.is_some())
+            .map(|(key, state)| self.due_at(key, state, now))
+            .min();
+    }
+
+    /// Send `update` of key `name`, bringing the client to `version`;
+    /// `whole` updates replace whatever the client has
+    fn deliver(
+        &mut self,
+        name: &str,
+        version: u64,
+        update: &RedisKeyUpdate,
+        whole: bool,
+        now: Instant
+    ) -> bool {
+        let state = self.key_states.entry(name.to_string()).or_insert(KeyState {
+            version: 0,
+            pending: None,
+            sent_at: None
+        });
+        let seq = self.seqs.get(name).map_or(1, |seq| seq + 1);
+
+        let filtered;
+        let update = match (self.field_filters.get(name), update) {
+            (Some(filter), RedisKeyUpdate::Hash(hash_update)) => {
+                let hash_update = hash_update.filtered(filter);
+                if hash_update.is_empty() && !whole {
+                    // nothing the client wants changed
+                    state.version = version;
+                    return false;
+                }
+                filtered = RedisKeyUpdate::Hash(hash_update);
+                &filtered
+            },
+            (_, update) => update
+        };
+        state.version = version;
+        state.sent_at = Some(now);
+        self.seqs.insert(name.to_string(), seq);
+        self.send(ServerMessage::Update { seq, whole, update });
+        metrics::UPDATES_SENT.with_label_values(&[&self.metrics_label]).inc();
+        true
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::{Duration, Instant};
+
+use redis::ConnectionLike;
+
+use crate::{config::RedisConfig, metrics};
+
+/// The broker's connection to Redis, counting the commands sent over it and
+/// timing their round trips
+pub struct Connection(redis::Connection);
+
+impl Connection {
+    fn timed<T>(&mut self, commands: usize, request: impl FnOnce(&mut redis::Connection) -> T) -> T {
+        let started = Instant::now();
+        let result = request(&mut self.0);
+        metrics::REDIS_LATENCY.observe(started.elapsed().as_secs_f64());
+        metrics::REDIS_COMMANDS.inc_by(commands as u64);
+        result
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        self.timed(1, |connection| connection.req_packed_command(cmd))
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        self.timed(offset + count, |connection| connection.req_packed_commands(cmd, offset, count))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.0.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.0.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.0.is_open()
+    }
+}
+
+/// Open a connection to Redis as described by `config`
+pub fn connect(config: &RedisConfig) -> redis::RedisResult<redis::Connection> {
+    let redis_client = redis::Client::open(config.connection_info()?)?;
+    let mut redis_connection = redis_client.get_connection_with_timeout(
+        Duration::from_millis(config.connect_timeout_ms)
+    )?;
+    let command_timeout = Some(Duration::from_millis(config.command_timeout_ms));
+    redis_connection.set_read_timeout(command_timeout)?;
+    redis_connection.set_write_timeout(command_timeout)?;
+    if let Some(client_name) = &config.client_name {
+        redis::cmd("CLIENT")
+            .arg("SETNAME")
+            .arg(client_name)
+            .query::<()>(&mut redis_connection)?;
+    }
+    Ok(redis_connection)
+}
+
+/// Whether `err` means the connection itself is unusable, rather than the
+/// command having been refused
+pub fn is_connection_error(err: &redis::RedisError) -> bool {
+    err.is_io_error()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_timeout()
+}
+
+/// Exponential backoff between reconnection attempts
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration
+}
+
+impl Backoff {
+    pub fn new(config: &RedisConfig) -> Backoff {
+        let initial = Duration::from_millis(config.reconnect_initial_ms);
+        Backoff {
+            initial,
+            max: Duration::from_millis(config.reconnect_max_ms).max(initial),
+            current: initial
+        }
+    }
+
+    /// The delay before the next attempt, doubling it for the one after
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// The broker's connection to Redis, re-established with backoff whenever
+/// it drops.
+pub struct RedisConnector {
+    config: RedisConfig,
+    connection: Option<Connection>,
+    backoff: Backoff,
+    next_attempt: Instant
+}
+
+impl RedisConnector {
+    /// Connect right away, starting out disconnected if Redis is unreachable
+    pub fn new(config: &RedisConfig) -> RedisConnector {
+        let mut connector = RedisConnector {
+            config: config.clone(),
+            connection: None,
+            backoff: Backoff::new(config),
+            next_attempt: Instant::now()
+        };
+        if !connector.reconnect() {
+            log::warn!("Redis unavailable at start up, retrying in the background");
+        }
+        connector
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// The live connection, `None` while Redis is down
+    pub fn connection(&mut self) -> Option<&mut Connection> {
+        self.connection.as_mut()
+    }
+
+    /// Drop the connection after it failed, the next attempt is made after a backoff
+    pub fn disconnect(&mut self) {
+        if self.connection.take().is_some() {
+            self.next_attempt = Instant::now() + self.backoff.next_delay();
+        }
+    }
+
+    /// When to next try reconnecting, `None` while connected
+    pub fn reconnect_deadline(&self) -> Option<Instant> {
+        match self.connection {
+            Some(_) => None,
+            None => Some(self.next_attempt)
+        }
+    }
+
+    /// Try to (re-)establish the connection, returns whether it is up
+    pub fn reconnect(&mut self) -> bool {
+        match connect(&self.config) {
+            Ok(connection) => {
+                self.connection = Some(Connection(connection));
+                self.backoff.reset();
+                true
+            },
+            Err(err) => {
+                let delay = self.backoff.next_delay();
+                log::warn!("cannot connect to Redis: {err}, retrying in {delay:?}");
+                self.next_attempt = Instant::now() + delay;
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::HashSet;
+
+use glob::Pattern;
+
+/// The fields of a hash a client asked for, by name or glob pattern
+#[derive(PartialEq)]
+pub struct FieldFilter {
+    names: HashSet<String>,
+    patterns: Vec<Pattern>
+}
+
+/// Whether `field` is meant as a glob pattern rather than a name
+fn is_pattern(field: &str) -> bool {
+    field.contains(['*', '?', '['])
+}
+
+impl FieldFilter {
+    /// The filter for `fields`, or the reason one of them can't be used
+    pub fn new(fields: Vec<String>) -> Result<FieldFilter, String> {
+        let mut names = HashSet::new();
+        let mut patterns = Vec::new();
+        for field in fields {
+            if is_pattern(&field) {
+                patterns.push(
+                    Pattern::new(&field).map_err(|err| format!("invalid field pattern {field}: {err}"))?
+                );
+            } else {
+                names.insert(field);
+            }
+        }
+        Ok(FieldFilter { names, patterns })
+    }
+
+    pub fn matches(&self, field: &str) -> bool {
+        self.names.contains(field) || self.patterns.iter().any(|pattern| pattern.matches(field))
+    }
+
+    /// The fields to fetch with HMGET, `None` if patterns need the whole hash
+    pub fn names(&self) -> Option<&HashSet<String>> {
+        if self.patterns.is_empty() {
+            Some(&self.names)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashSet,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant}
+};
+
+use redis::{Msg, Value};
+
+use crate::{
+    config::RedisConfig,
+    server::{
+        BrokerMessage,
+        BrokerSender,
+        connection::{self, Backoff}
+    }
+};
+
+/// Event classes the broker needs published: generic (del, rename, expire),
+/// the commands of every key type it reads and expiry
+const KEYSPACE_EVENT_CLASSES: &str = "g$lshztx";
+
+/// How long the listener blocks on Redis before checking for new subscriptions
+const LISTEN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something Redis told us about a key
+pub enum KeyspaceEvent {
+    /// The listener is now receiving events for the key, anything earlier may have been missed
+    Subscribed(String),
+    /// Redis does not publish the events needed, the listener has given up
+    Unavailable,
+    /// The listener's connection dropped, Redis itself may be down
+    ConnectionLost,
+    /// The listener is now receiving events for keys matching the pattern
+    PatternSubscribed(String),
+    /// The key was touched by the named command, e.g. `hset`, `del` or `expired`
+    Changed {
+        key: String,
+        event: String
+    },
+    /// As `Changed`, for a key heard of through a pattern subscription
+    PatternChanged {
+        pattern: String,
+        key: String,
+        event: String
+    }
+}
+
+enum ListenerCommand {
+    Subscribe(String),
+    Unsubscribe(String),
+    PSubscribe(String),
+    PUnsubscribe(String)
+}
+
+/// The keys and patterns subscribed to, for resubscribing after a reconnect
+#[derive(Default)]
+struct Subscriptions {
+    keys: HashSet<String>,
+    patterns: HashSet<String>
+}
+
+impl ListenerCommand {
+    fn apply(&self, subscriptions: &mut Subscriptions) {
+        match self {
+            ListenerCommand::Subscribe(key) => subscriptions.keys.insert(key.clone()),
+            ListenerCommand::Unsubscribe(key) => subscriptions.keys.remove(key),
+            ListenerCommand::PSubscribe(pattern) => subscriptions.patterns.insert(pattern.clone()),
+            ListenerCommand::PUnsubscribe(pattern) => subscriptions.patterns.remove(pattern)
+        };
+    }
+
+    fn redis_command(&self, channel_prefix: &str) -> redis::Cmd {
+        let (command, key) = match self {
+            ListenerCommand::Subscribe(key) => ("SUBSCRIBE", key),
+            ListenerCommand::Unsubscribe(key) => ("UNSUBSCRIBE", key),
+            ListenerCommand::PSubscribe(pattern) => ("PSUBSCRIBE", pattern),
+            ListenerCommand::PUnsubscribe(pattern) => ("PUNSUBSCRIBE", pattern)
+        };
+        let mut command = redis::cmd(command);
+        command.arg(format!("{channel_prefix}{key}"));
+        command
+    }
+}
+
+fn covers(flags: &str, class: char) -> bool {
+    flags.contains(class) || (flags.contains('A') && "g$lshzxet".contains(class))
+}
+
+/// Make sure Redis publishes the keyspace events the broker relies on,
+/// switching them on with `CONFIG SET` if `configure` allows it.
+///
+/// Returns whether notifications can be used.
+pub fn ensure_keyspace_events(
+    redis_connection: &mut redis::Connection,
+    configure: bool
+) -> redis::RedisResult<bool> {
+    let reply: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query(redis_connection)?;
+    let mut flags = reply.get(1).cloned().unwrap_or_default();
+
+    let missing: String = ['K'].into_iter()
+        .filter(|class| !flags.contains(*class))
+        .chain(KEYSPACE_EVENT_CLASSES.chars().filter(|class| !covers(&flags, *class)))
+        .collect();
+    if missing.is_empty() {
+        return Ok(true);
+    }
+    if !configure {
+        log::warn!("notify-keyspace-events is \"{flags}\", lacking \"{missing}\"");
+        return Ok(false);
+    }
+
+    flags.push_str(&missing);
+    redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("notify-keyspace-events")
+        .arg(&flags)
+        .query::<()>(redis_connection)?;
+    log::info!("set notify-keyspace-events to \"{flags}\"");
+    Ok(true)
+}
+
+/// Handle on the thread that subscribes to `__keyspace@<db>__:<key>`
+/// channels and forwards what it hears to the broker.
+///
+/// The thread reconnects on its own, resubscribing to every key it had.
+pub struct KeyspaceListener {
+    commands: Sender<ListenerCommand>,
+    thread: thread::JoinHandle<()>
+}
+
+impl KeyspaceListener {
+    pub fn start(
+        config: &RedisConfig,
+        configure: bool,
+        tx: BrokerSender
+    ) -> redis::RedisResult<KeyspaceListener> {
+        let db = config.connection_info()?.redis.db;
+        let channel_prefix = format!("__keyspace@{db}__:");
+        let config = config.clone();
+        let (commands, rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || run(&config, configure, &channel_prefix, rx, tx));
+
+        Ok(KeyspaceListener { commands, thread })
+    }
+
+    /// Have the thread close its connection and wait for it to finish
+    pub fn stop(self) {
+        // the thread notices within LISTEN_INTERVAL that the broker has gone
+        drop(self.commands);
+        if self.thread.join().is_err() {
+            log::error!("keyspace listener panicked");
+        }
+    }
+
+    pub fn subscribe(&self, key: &str) {
+        let _ = self.commands.send(ListenerCommand::Subscribe(key.to_string()));
+    }
+
+    pub fn unsubscribe(&self, key: &str) {
+        let _ = self.commands.send(ListenerCommand::Unsubscribe(key.to_string()));
+    }
+
+    pub fn psubscribe(&self, pattern: &str) {
+        let _ = self.commands.send(ListenerCommand::PSubscribe(pattern.to_string()));
+    }
+
+    pub fn punsubscribe(&self, pattern: &str) {
+        let _ = self.commands.send(ListenerCommand::PUnsubscribe(pattern.to_string()));
+    }
+}
+
+/// A connection ready for subscribing, `None` if Redis won't publish the
+/// events or won't say whether it does
+fn connect(config: &RedisConfig, configure: bool) -> redis::RedisResult<Option<redis::Connection>> {
+    let mut redis_connection = connection::connect(config)?;
+    match ensure_keyspace_events(&mut redis_connection, configure) {
+        Ok(true) => (),
+        Ok(false) => return Ok(None),
+        Err(err) if connection::is_connection_error(&err) => return Err(err),
+        // e.g. CONFIG renamed away or refused by an ACL, retrying won't help
+        Err(err) => {
+            log::warn!("cannot check notify-keyspace-events: {err}");
+            return Ok(None);
+        }
+    }
+    redis_connection.set_read_timeout(Some(LISTEN_INTERVAL))?;
+    Ok(Some(redis_connection))
+}
+
+fn run(
+    config: &RedisConfig,
+    configure: bool,
+    channel_prefix: &str,
+    commands: Receiver<ListenerCommand>,
+    tx: BrokerSender
+) {
+    let mut subscriptions = Subscriptions::default();
+    let mut backoff = Backoff::new(config);
+    loop {
+        match connect(config, configure) {
+            Ok(Some(redis_connection)) => {
+                backoff.reset();
+                match listen(redis_connection, channel_prefix, &commands, &mut subscriptions, &tx) {
+                    Ok(()) => return,
+                    Err(err) => {
+                        log::warn!("keyspace listener lost its connection: {err}");
+                        tx.send(BrokerMessage::Keyspace(KeyspaceEvent::ConnectionLost));
+                    }
+                }
+            },
+            Ok(None) => {
+                tx.send(BrokerMessage::Keyspace(KeyspaceEvent::Unavailable));
+                return;
+            },
+            Err(err) => log::warn!("keyspace listener cannot connect: {err}")
+        }
+
+        // wait before retrying, keeping up with subscription changes meanwhile
+        let retry_at = Instant::now() + backoff.next_delay();
+        while let Some(timeout) = retry_at.checked_duration_since(Instant::now()) {
+            match commands.recv_timeout(timeout) {
+                Ok(command) => command.apply(&mut subscriptions),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+        }
+    }
+}
+
+/// Forward events until the broker goes away (`Ok`) or the connection fails.
+///
+/// Subscription commands are sent without waiting for their replies, so
+/// every reply, confirmation or message, is read in one place.
+fn listen(
+    mut redis_connection: redis::Connection,
+    channel_prefix: &str,
+    commands: &Receiver<ListenerCommand>,
+    subscriptions: &mut Subscriptions,
+    tx: &BrokerSender
+) -> redis::RedisResult<()> {
+    for (command, names) in [
+        ("SUBSCRIBE", &subscriptions.keys),
+        ("PSUBSCRIBE", &subscriptions.patterns)
+    ] {
+        if !names.is_empty() {
+            let channels: Vec<String> = names.iter()
+                .map(|name| format!("{channel_prefix}{name}"))
+                .collect();
+            redis_connection.send_packed_command(
+                &redis::cmd(command).arg(channels).get_packed_command()
+            )?;
+        }
+    }
+
+    loop {
+        loop {
+            let command = match commands.try_recv() {
+                Ok(command) => command,
+                Err(TryRecvError::Empty) => break,
+                // broker has gone
+                Err(TryRecvError::Disconnected) => return Ok(())
+            };
+            command.apply(subscriptions);
+            redis_connection.send_packed_command(
+                &command.redis_command(channel_prefix).get_packed_command()
+            )?;
+        }
+
+        let reply = match redis_connection.recv_response() {
+            Ok(reply) => reply,
+            Err(err) if err.is_timeout() => continue,
+            Err(err) => return Err(err)
+        };
+
+        let event = if let Some(msg) = Msg::from_value(&reply) {
+            keyspace_change(&msg, channel_prefix)
+        } else {
+            subscription_confirmation(&reply, channel_prefix)
+        };
+        if let Some(event) = event {
+            if !tx.send(BrokerMessage::Keyspace(event)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn keyspace_change(msg: &Msg, channel_prefix: &str) -> Option<KeyspaceEvent> {
+    let key = msg.get_channel_name().strip_prefix(channel_prefix)?.to_string();
+    let event = msg.get_payload().ok()?;
+    if msg.from_pattern() {
+        let pattern: String = msg.get_pattern().ok()?;
+        Some(KeyspaceEvent::PatternChanged {
+            pattern: pattern.strip_prefix(channel_prefix)?.to_string(),
+            key,
+            event
+        })
+    } else {
+        Some(KeyspaceEvent::Changed { key, event })
+    }
+}
+
+fn subscription_confirmation(reply: &Value, channel_prefix: &str) -> Option<KeyspaceEvent> {
+    match reply {
+        Value::Bulk(items) => match items.as_slice() {
+            [Value::Data(kind), Value::Data(channel), ..] => {
+                let channel = String::from_utf8_lossy(channel);
+                let name = channel.strip_prefix(channel_prefix)?.to_string();
+                match kind.as_slice() {
+                    b"subscribe" => Some(KeyspaceEvent::Subscribed(name)),
+                    b"psubscribe" => Some(KeyspaceEvent::PatternSubscribed(name)),
+                    _ => None
+                }
+            },
+            _ => None
+        },
+        _ => None
+    }
+}
@@ -1,27 +1,76 @@
+mod broker;
+mod connection;
+mod field_filter;
+mod keyspace;
 mod redis_hash;
+mod redis_key;
+mod schedule;
+mod snapshot;
+mod write;
 pub mod client;
+pub mod query;
 
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    sync::{mpsc::{self, Sender, TryRecvError}, Mutex, Arc},
-    thread
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+        Mutex
+    },
+    thread,
+    time::{Duration, Instant}
 };
 
 use actix::prelude::*;
-
-use redis::Commands;
+use tokio::sync::oneshot;
 
 use crate::{
+    acl::AccessControl,
+    metrics,
+    auth::Identity,
+    config::{BrokerConfig, RedisConfig},
     server::{
-        redis_hash::RedisHash,
-        client::{Client, JsonMessage}
+        broker::Broker,
+        client::SessionLink,
+        connection::RedisConnector,
+        keyspace::{KeyspaceEvent, KeyspaceListener},
+        query::{Query, QueryAnswer, QueryError, QueryReply}
     },
-    session::client_action::{ClientAction, ClientActions}
+    session::{client_action::ClientAction, protocol::Resume}
 };
 
+/// The server is going away: the session closes, telling its client to come
+/// back after `retry_after`
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub retry_after: Duration
+}
+
+impl Shutdown {
+    /// Why the session closes, for its client
+    pub fn reason(&self) -> String {
+        format!("server restarting, retry after {} seconds", self.retry_after.as_millis().div_ceil(1000))
+    }
+}
+
 pub enum SessionMessages {
+    /// The session has started, to be closed through `closer` on shutdown
+    Open {
+        closer: Recipient<Shutdown>
+    },
     Disconnect,
-    Connect(Recipient<JsonMessage>),
+    /// The session has settled on a protocol version and encoding and is
+    /// ready for messages
+    Connect {
+        link: SessionLink,
+        /// who the session authenticated as
+        identity: Identity,
+        /// lets a later session resume this one
+        resume_token: Option<String>,
+        /// an earlier session to resume
+        resume: Option<Resume>
+    },
     Action(ClientAction)
 }
 
@@ -30,143 +79,143 @@ pub struct SessionMessage {
     pub message: SessionMessages
 }
 
+/// Everything the broker thread is told about
+pub enum BrokerMessage {
+    Session(SessionMessage),
+    Keyspace(KeyspaceEvent),
+    /// A one-off read, answered on `reply`
+    Query {
+        query: Query,
+        reply: QueryReply
+    },
+    /// The access rules changed, subscriptions are checked against them again
+    AccessChanged,
+    /// Close every session and leave the broker loop
+    Stop {
+        retry_after: Duration
+    }
+}
+
+impl From<SessionMessage> for BrokerMessage {
+    fn from(message: SessionMessage) -> Self {
+        BrokerMessage::Session(message)
+    }
+}
+
+/// The sending end of the broker's channel, keeping count of the messages
+/// waiting for it
+#[derive(Clone)]
+pub struct BrokerSender(Sender<BrokerMessage>);
+
+impl BrokerSender {
+    /// Queue `message` for the broker, returns whether its thread is still there to receive it
+    pub fn send(&self, message: BrokerMessage) -> bool {
+        metrics::BROKER_QUEUE.inc();
+        if self.0.send(message).is_err() {
+            metrics::BROKER_QUEUE.dec();
+            return false;
+        }
+        true
+    }
+}
+
+/// Process-wide handle on the broker thread, shared by every HTTP worker
 pub struct RedisHashBroker {
-    next_client_id: Arc<Mutex<usize>>,
-    redis_thread: thread::JoinHandle<()>,
-    tx: Sender<SessionMessage>
+    next_client_id: AtomicUsize,
+    /// set once asked to stop, new sessions are turned away from then on
+    stopping: AtomicBool,
+    /// taken when the broker is stopped
+    redis_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    tx: BrokerSender
 }
 
 impl RedisHashBroker {
-    pub fn new() -> RedisHashBroker {
-        
-        let (tx, rx) = mpsc::channel();
+    pub fn new(
+        redis_config: &RedisConfig,
+        broker_config: &BrokerConfig,
+        acl: Arc<AccessControl>
+    ) -> redis::RedisResult<RedisHashBroker> {
+        // fail early on a malformed URL, connection failures are retried
+        redis_config.connection_info()?;
 
-        RedisHashBroker {
-            next_client_id: Arc::new(Mutex::new(0)),
-            redis_thread: thread::spawn(move || {
-                let mut clients: HashMap<usize, Client> = HashMap::new();
-                let mut hashrequest_clients: HashMap<String, HashSet<usize>> = HashMap::new();
-                let mut hashrequest_queue: VecDeque<String> = VecDeque::new();
-
-                let redis_client = redis::Client::open("redis://redishost:6379").unwrap();
-                let mut redis_connection = redis_client.get_connection().unwrap();
-
-                loop {
-                    match rx.try_recv() {
-                        Ok(SessionMessage {
-                            id,
-                            message: SessionMessages::Disconnect
-                        }) => {
-                            if clients.remove(&id).is_some() {
-                                for (_hash, clients) in &mut hashrequest_clients {
-                                    clients.remove(&id);
-                                }
-                            }
-                        },
-
-                        Ok(SessionMessage {
-                            id,
-                            message: SessionMessages::Connect(addr)
-                        }) => {
-                            clients.insert(
-                                id,
-                                Client::new(addr)
-                            );
-                        },
-
-                        Ok(SessionMessage {
-                            id,
-                            message: SessionMessages::Action(
-                                ClientAction {
-                                    action : ClientActions::Request,
-                                    hash_names
-                                }
-                            )
-                        }) => {                            
-                            for hash in hash_names {
-                                // file client's hash-requests
-                                hashrequest_clients
-                                    .entry(hash.clone())
-                                    .or_insert_with(HashSet::new)
-                                    .insert(id);
-
-                                if !hashrequest_queue.contains(&hash) {
-                                    hashrequest_queue.push_back(hash);
-                                }
-                            }
-                        },
-
-                        Ok(SessionMessage {
-                            id,
-                            message: SessionMessages::Action(
-                                ClientAction {
-                                    action : ClientActions::Drop,
-                                    hash_names
-                                }
-                            )
-                        }) => {
-                            let client = clients.get_mut(&id).unwrap();
-                            
-                            for hash in hash_names {
-                                // remove from running list
-                                client.handle_drop(&hash);
-
-                                // remove from hash's clients
-                                if let Some(hash_clients) = hashrequest_clients.get_mut(&hash) {
-                                    hash_clients.remove(&id);
-                                }
-                            }
-                        },
-
-                        Err(TryRecvError::Empty) => (),
-                        Err(TryRecvError::Disconnected) => {
-                            break;
-                        }
-                    }
-                
-                    if let Some(hash) = hashrequest_queue.pop_front() {
-                        let mut hash_clients = hashrequest_clients.remove(&hash).unwrap();
-                        if hash_clients.len() > 0 {
-                            let redishash = RedisHash {
-                                name: hash.clone(),
-                                contents: redis_connection.hgetall(&hash).unwrap()
-                            };
-                            
-                            for clientid in hash_clients.drain() {
-                                match clients.get_mut(&clientid) {
-                                    Some(client) => {
-                                        if !client.update_hash(&redishash) {
-                                            // no update, re-constitute request
-                                            hashrequest_queue.push_back(hash.clone());
-
-                                            hashrequest_clients
-                                                .entry(hash.clone())
-                                                .or_insert_with(HashSet::new)
-                                                .insert(clientid);
-                                        }
-                                    },
-                                    None => {
-                                        // should probably error
-                                    }
-                                }
-                            }
-                        }
-
-                    }
-                }
-            }),
-            tx: tx
-        }
+        let (tx, rx) = mpsc::channel();
+        let tx = BrokerSender(tx);
+
+        let listener = if broker_config.keyspace_notifications {
+            Some(KeyspaceListener::start(
+                redis_config,
+                broker_config.configure_keyspace_events,
+                tx.clone()
+            )?)
+        } else {
+            None
+        };
+
+        let broker = Broker::new(RedisConnector::new(redis_config), listener, broker_config, acl);
+
+        Ok(RedisHashBroker {
+            next_client_id: AtomicUsize::new(0),
+            stopping: AtomicBool::new(false),
+            redis_thread: Mutex::new(Some(thread::spawn(move || broker.run(rx)))),
+            tx
+        })
     }
 
-    pub fn clone_tx(&self) -> Sender<SessionMessage> {
+    pub fn clone_tx(&self) -> BrokerSender {
         self.tx.clone()
     }
 
     pub fn take_next_client_id(&self) -> usize {
-        let mut next_client_id = self.next_client_id.lock().unwrap();
-        let client_id = *next_client_id;
-        *next_client_id += 1;
-        client_id
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Whether the broker thread is still running, rather than stopped or panicked
+    pub fn is_running(&self) -> bool {
+        self.redis_thread.lock().unwrap().as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Have the broker apply reloaded access rules to existing subscriptions
+    pub fn access_changed(&self) {
+        self.tx.send(BrokerMessage::AccessChanged);
+    }
+
+    /// Have the broker thread read something from Redis once
+    pub async fn query(&self, query: Query) -> Result<QueryAnswer, QueryError> {
+        let (reply, answer) = oneshot::channel();
+        if !self.tx.send(BrokerMessage::Query { query, reply }) {
+            return Err(QueryError::Unavailable);
+        }
+        answer.await.unwrap_or(Err(QueryError::Unavailable))
+    }
+
+    /// Have the broker close every session, telling clients to retry after
+    /// `retry_after`, and finish; its connections to Redis close as it does
+    pub fn stop(&self, retry_after: Duration) {
+        self.stopping.store(true, Ordering::Relaxed);
+        self.tx.send(BrokerMessage::Stop { retry_after });
+    }
+
+    /// Whether the broker was asked to stop, and so takes no new sessions
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// Wait until `deadline` for the broker thread to finish, returns whether it did
+    pub fn join(&self, deadline: Instant) -> bool {
+        // the lock is only held to look, `is_running` mustn't wait on the sleep
+        while self.is_running() {
+            if Instant::now() >= deadline {
+                log::warn!("broker thread still busy, leaving it behind");
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let redis_thread = self.redis_thread.lock().unwrap().take();
+        if let Some(redis_thread) = redis_thread {
+            if redis_thread.join().is_err() {
+                log::error!("broker thread panicked");
+            }
+        }
+        true
     }
 }
@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::server::{connection::Connection, redis_hash::HashScan};
+
+/// A one-off read outside any session, for the HTTP API
+pub enum Query {
+    /// whether Redis answers at all
+    Ping,
+    /// every field of a hash
+    Hash(String),
+    /// one field of a hash
+    Field { hash: String, field: String },
+    /// a page of the keys matching `pattern`, only those of `key_type` if given
+    Keys {
+        pattern: String,
+        key_type: Option<String>,
+        cursor: u64,
+        count: usize
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum QueryAnswer {
+    /// fields in order, so equal hashes serialise alike
+    Hash(BTreeMap<String, String>),
+    Field(String),
+    Keys {
+        keys: Vec<String>,
+        /// where the next page starts, `None` after the last
+        cursor: Option<u64>
+    },
+    /// Redis answered a ping
+    Pong
+}
+
+/// Why a query could not be answered
+pub enum QueryError {
+    /// the hash does not exist
+    MissingKey,
+    /// the hash exists without the field
+    MissingField,
+    /// Redis is unreachable
+    Unavailable,
+    Redis(redis::RedisError)
+}
+
+impl From<redis::RedisError> for QueryError {
+    fn from(err: redis::RedisError) -> Self {
+        QueryError::Redis(err)
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::MissingKey => f.write_str("no such hash"),
+            QueryError::MissingField => f.write_str("no such field"),
+            QueryError::Unavailable => f.write_str("Redis is unreachable"),
+            QueryError::Redis(err) => err.fmt(f)
+        }
+    }
+}
+
+/// Where the answer to a query goes
+pub type QueryReply = oneshot::Sender<Result<QueryAnswer, QueryError>>;
+
+/// A hash too large to read at once, read for a query a page at a time in
+/// between the broker's other reads
+pub struct HashQuery {
+    pub hash: String,
+    pub scan: HashScan,
+    pub reply: QueryReply
+}
+
+impl Query {
+    pub fn run(self, redis_connection: &mut Connection) -> Result<QueryAnswer, QueryError> {
+        match self {
+            Query::Ping => {
+                redis::cmd("PING").query::<()>(redis_connection)?;
+                Ok(QueryAnswer::Pong)
+            },
+            Query::Hash(hash) => {
+                let contents: BTreeMap<String, String> = redis::cmd("HGETALL")
+                    .arg(&hash)
+                    .query(redis_connection)?;
+                // Redis has no empty hashes
+                if contents.is_empty() {
+                    return Err(QueryError::MissingKey);
+                }
+                Ok(QueryAnswer::Hash(contents))
+            },
+            Query::Field { hash, field } => {
+                let value: Option<String> = redis::cmd("HGET")
+                    .arg(&hash)
+                    .arg(&field)
+                    .query(redis_connection)?;
+                match value {
+                    Some(value) => Ok(QueryAnswer::Field(value)),
+                    None => {
+                        let exists: bool = redis::cmd("EXISTS").arg(&hash).query(redis_connection)?;
+                        Err(if exists { QueryError::MissingField } else { QueryError::MissingKey })
+                    }
+                }
+            },
+            Query::Keys { pattern, key_type, cursor, count } => {
+                let mut scan = redis::cmd("SCAN");
+                scan.arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(count);
+                if let Some(key_type) = &key_type {
+                    scan.arg("TYPE").arg(key_type);
+                }
+                let (next, mut keys): (u64, Vec<String>) = scan.query(redis_connection)?;
+                keys.sort();
+                Ok(QueryAnswer::Keys {
+                    keys,
+                    cursor: if next == 0 { None } else { Some(next) }
+                })
+            }
+        }
+    }
+}
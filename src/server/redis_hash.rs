@@ -1,10 +1,12 @@
-use std::{collections::{HashMap, HashSet}, fmt};
+use std::collections::{HashMap, HashSet};
 
-use serde::{Deserialize, Serialize, Serializer, ser::SerializeMap, Deserializer, de::{Visitor, MapAccess}};
+use serde::Serialize;
+
+use crate::{metrics, server::{connection::Connection, field_filter::FieldFilter}};
 
 pub type RedisHashContents = HashMap<String, String>;
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct RedisHashContentsUpdate {
     name: String,
     upsert: RedisHashContents,
@@ -13,40 +15,42 @@ pub struct RedisHashContentsUpdate {
 
 impl RedisHashContentsUpdate {
     pub fn from(
-        contemporary: &RedisHash,
-        previous: &Option<RedisHashContents>
+        name: &str,
+        contemporary: &RedisHashContents,
+        previous: Option<&RedisHashContents>
     ) -> Option<RedisHashContentsUpdate> {
-        let name = contemporary.name.clone();
-        
+        let name = name.to_string();
+
         match previous {
             None => {
                 return Some(RedisHashContentsUpdate {
                     name,
-                    upsert: contemporary.contents.clone(),
+                    upsert: contemporary.clone(),
                     delete: HashSet::new()
                 });
             }
             Some(previous_content) => {
                 let mut upsert = RedisHashContents::new();
-                for (key, value) in contemporary.contents.iter() {
+                for (key, value) in contemporary.iter() {
                     if let Some(previous_value) = previous_content.get(key) {
                         if previous_value.eq(value) {
                             continue;
                         }
                     }
-        
+
                     upsert.insert(key.clone(), value.clone());
                 }
                 let delete: HashSet<String> = previous_content.keys()
                     .filter(
-                        |k| !contemporary.contents.contains_key(*k)
+                        |k| !contemporary.contains_key(*k)
                     ).map(
                         |k| k.clone()
                     ).collect();
-                
+
                 if delete.len() == 0 && upsert.len() == 0 {
                     return None
                 }
+                metrics::HASH_DIFF_FIELDS.observe((upsert.len() + delete.len()) as f64);
 
                 return Some(RedisHashContentsUpdate {
                     name,
@@ -56,66 +60,109 @@ impl RedisHashContentsUpdate {
             }
         }
     }
-}
 
-pub struct RedisHash {
-    pub name: String,
-    pub contents: RedisHashContents
-}
+    /// Fields of `page`, part of a hash read in pages, that `previous`
+    /// lacks or holds other values for; deletions only show once every
+    /// page is read
+    pub fn from_page(
+        name: &str,
+        page: &RedisHashContents,
+        previous: &RedisHashContents
+    ) -> Option<RedisHashContentsUpdate> {
+        let upsert: RedisHashContents = page.iter()
+            .filter(|(field, value)| previous.get(*field) != Some(*value))
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        if upsert.is_empty() {
+            return None;
+        }
+        metrics::HASH_DIFF_FIELDS.observe(upsert.len() as f64);
+        Some(RedisHashContentsUpdate {
+            name: name.to_string(),
+            upsert,
+            delete: HashSet::new()
+        })
+    }
 
-impl Serialize for RedisHash {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut map = serializer.serialize_map(Some(1))?;
-        
-        map.serialize_entry(
-            &self.name,
-            &self.contents
-        )?;
-        map.end()
+    /// The part of the update about fields `filter` lets through
+    pub fn filtered(&self, filter: &FieldFilter) -> RedisHashContentsUpdate {
+        RedisHashContentsUpdate {
+            name: self.name.clone(),
+            upsert: self.upsert.iter()
+                .filter(|(field, _)| filter.matches(field))
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect(),
+            delete: self.delete.iter().filter(|field| filter.matches(field)).cloned().collect()
+        }
     }
-}
 
-impl<'de> Deserialize<'de> for RedisHash
-{
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        struct RedisHashVisitor;
+    pub fn is_empty(&self) -> bool {
+        self.upsert.is_empty() && self.delete.is_empty()
+    }
+
+    /// Fold `next` into this update, as if both had been applied in turn
+    pub fn merge(&mut self, next: &RedisHashContentsUpdate) {
+        for (field, value) in &next.upsert {
+            self.delete.remove(field);
+            self.upsert.insert(field.clone(), value.clone());
+        }
+        for field in &next.delete {
+            self.upsert.remove(field);
+            self.delete.insert(field.clone());
+        }
+    }
 
-        impl<'de> Visitor<'de> for RedisHashVisitor {
-            type Value = RedisHash;
+    /// Bring `contents` up to date, as the client does
+    pub fn apply(&self, contents: &mut RedisHashContents) {
+        contents.extend(self.upsert.iter().map(|(field, value)| (field.clone(), value.clone())));
+        for field in &self.delete {
+            contents.remove(field);
+        }
+    }
+}
 
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("single entry map<str, map<str, str>>")
-            }
+/// A hash too large to read at once, read a page at a time with HSCAN so
+/// that Redis can serve others in between
+pub struct HashScan {
+    /// where the next page starts, `None` once every page is read
+    cursor: Option<u64>,
+    /// fields read so far
+    pub contents: RedisHashContents,
+    /// the hash changed while being read, so earlier pages may be out of date
+    pub stale: bool
+}
 
-            fn visit_map<V>(self, mut map: V) -> Result<RedisHash, V::Error>
-            where
-                V: MapAccess<'de>,
-            {
-                let mut name: Option<String> = None;
-                let mut contents: Option<RedisHashContents> = None;
-                while let Some(name_) = map.next_key()? {
-                    if name.is_none() {
-                        name = Some(name_);
-                        contents = Some(map.next_value()?);
-                    } else {
-                        return Err(serde::de::Error::duplicate_field("name"));
-                    }
-                }
-                let name = name.ok_or_else(|| serde::de::Error::missing_field("name"))?;
-                let contents = contents.unwrap_or(RedisHashContents::new());
-                Ok(RedisHash {
-                    name: name,
-                    contents: contents
-                })
-            }
+impl HashScan {
+    pub fn new() -> HashScan {
+        HashScan {
+            cursor: Some(0),
+            contents: RedisHashContents::new(),
+            stale: false
         }
+    }
 
-        deserializer.deserialize_map(RedisHashVisitor)
+    pub fn is_complete(&self) -> bool {
+        self.cursor.is_none()
     }
-}
\ No newline at end of file
+
+    /// Read the next page of roughly `page_size` fields, returning it
+    pub fn next_page(
+        &mut self,
+        redis_connection: &mut Connection,
+        name: &str,
+        page_size: usize
+    ) -> redis::RedisResult<RedisHashContents> {
+        let Some(cursor) = self.cursor else {
+            return Ok(RedisHashContents::new());
+        };
+        let (next, page): (u64, RedisHashContents) = redis::cmd("HSCAN")
+            .arg(name)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(page_size)
+            .query(redis_connection)?;
+        self.cursor = if next == 0 { None } else { Some(next) };
+        self.contents.extend(page.iter().map(|(field, value)| (field.clone(), value.clone())));
+        Ok(page)
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::server::{
+    connection::Connection,
+    redis_hash::{RedisHashContents, RedisHashContentsUpdate}
+};
+
+pub type RedisListContents = Vec<String>;
+pub type RedisSetContents = HashSet<String>;
+/// member to score
+pub type RedisZsetContents = HashMap<String, f64>;
+
+#[derive(Serialize, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub id: String,
+    pub fields: HashMap<String, String>
+}
+
+/// A stream id `<ms>-<seq>` in comparable form
+fn stream_id(id: &str) -> (u64, u64) {
+    let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (ms.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+}
+
+/// Those of `fields` that hash `name` has, with their values
+fn read_hash_fields(
+    redis_connection: &mut Connection,
+    name: &str,
+    fields: &HashSet<String>
+) -> redis::RedisResult<RedisHashContents> {
+    if fields.is_empty() {
+        return Ok(RedisHashContents::new());
+    }
+    let fields: Vec<&String> = fields.iter().collect();
+    let values: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(name)
+        .arg(&fields)
+        .query(redis_connection)?;
+    Ok(fields.into_iter()
+        .zip(values)
+        .filter_map(|(field, value)| Some((field.clone(), value?)))
+        .collect())
+}
+
+/// What a key held when it was read, by type
+#[derive(Clone)]
+pub enum RedisKeyContents {
+    /// the key does not exist
+    None,
+    String(String),
+    Hash(RedisHashContents),
+    List(RedisListContents),
+    Set(RedisSetContents),
+    Zset(RedisZsetContents),
+    /// the latest entries, oldest first
+    Stream(Vec<StreamEntry>)
+}
+
+impl RedisKeyContents {
+    /// Read `name` whichever type it has, taking at most `stream_backlog`
+    /// entries of a stream.
+    ///
+    /// Of a hash only `fields` are read if given. Otherwise `None` if `name`
+    /// is a hash of more than `hscan_threshold` fields, which is left to be
+    /// read in pages.
+    pub fn read(
+        redis_connection: &mut Connection,
+        name: &str,
+        stream_backlog: usize,
+        hscan_threshold: usize,
+        fields: Option<&HashSet<String>>
+    ) -> redis::RedisResult<Option<RedisKeyContents>> {
+        let key_type: String = redis::cmd("TYPE").arg(name).query(redis_connection)?;
+        let contents = match key_type.as_str() {
+            "none" => RedisKeyContents::None,
+            "string" => RedisKeyContents::String(
+                redis::cmd("GET").arg(name).query(redis_connection)?
+            ),
+            "hash" => {
+                if let Some(fields) = fields {
+                    return Ok(Some(RedisKeyContents::Hash(
+                        read_hash_fields(redis_connection, name, fields)?
+                    )));
+                }
+                if hscan_threshold > 0 {
+                    let length: usize = redis::cmd("HLEN").arg(name).query(redis_connection)?;
+                    if length > hscan_threshold {
+                        return Ok(None);
+                    }
+                }
+                RedisKeyContents::Hash(
+                    redis::cmd("HGETALL").arg(name).query(redis_connection)?
+                )
+            },
+            "list" => RedisKeyContents::List(
+                redis::cmd("LRANGE").arg(name).arg(0).arg(-1).query(redis_connection)?
+            ),
+            "set" => RedisKeyContents::Set(
+                redis::cmd("SMEMBERS").arg(name).query(redis_connection)?
+            ),
+            "zset" => {
+                let members: Vec<(String, f64)> = redis::cmd("ZRANGE")
+                    .arg(name)
+                    .arg(0)
+                    .arg(-1)
+                    .arg("WITHSCORES")
+                    .query(redis_connection)?;
+                RedisKeyContents::Zset(members.into_iter().collect())
+            },
+            "stream" => {
+                // parsed one by one, a Vec of tuples would be read as flat pairs
+                let entries: Vec<redis::Value> = redis::cmd("XREVRANGE")
+                    .arg(name)
+                    .arg("+")
+                    .arg("-")
+                    .arg("COUNT")
+                    .arg(stream_backlog)
+                    .query(redis_connection)?;
+                let mut stream = Vec::with_capacity(entries.len());
+                for entry in entries.iter().rev() {
+                    let (id, fields) = redis::from_redis_value(entry)?;
+                    stream.push(StreamEntry { id, fields });
+                }
+                RedisKeyContents::Stream(stream)
+            },
+            other => return Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "unsupported key type",
+                other.to_string()
+            )))
+        };
+        Ok(Some(contents))
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct RedisStringUpdate {
+    name: String,
+    value: String
+}
+
+impl RedisStringUpdate {
+    pub fn from(
+        name: &str,
+        contemporary: &String,
+        previous: Option<&String>
+    ) -> Option<RedisStringUpdate> {
+        if previous == Some(contemporary) {
+            return None;
+        }
+        Some(RedisStringUpdate {
+            name: name.to_string(),
+            value: contemporary.clone()
+        })
+    }
+}
+
+/// Replaces `delete_count` items from `index` on with `insert`, like `Array.splice`
+#[derive(Serialize, Clone)]
+pub struct RedisListUpdate {
+    name: String,
+    index: usize,
+    delete_count: usize,
+    insert: Vec<String>
+}
+
+impl RedisListUpdate {
+    pub fn from(
+        name: &str,
+        contemporary: &RedisListContents,
+        previous: Option<&RedisListContents>
+    ) -> Option<RedisListUpdate> {
+        if previous == Some(contemporary) {
+            return None;
+        }
+        let empty = RedisListContents::new();
+        let previous = previous.unwrap_or(&empty);
+
+        // only the middle part between the common head and tail changed
+        let head = previous.iter()
+            .zip(contemporary)
+            .take_while(|(old, new)| old == new)
+            .count();
+        let tail = previous[head..].iter().rev()
+            .zip(contemporary[head..].iter().rev())
+            .take_while(|(old, new)| old == new)
+            .count();
+
+        Some(RedisListUpdate {
+            name: name.to_string(),
+            index: head,
+            delete_count: previous.len() - head - tail,
+            insert: contemporary[head..contemporary.len() - tail].to_vec()
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct RedisSetUpdate {
+    name: String,
+    add: RedisSetContents,
+    remove: RedisSetContents
+}
+
+impl RedisSetUpdate {
+    fn merge(&mut self, next: &RedisSetUpdate) {
+        for member in &next.add {
+            self.remove.remove(member);
+            self.add.insert(member.clone());
+        }
+        for member in &next.remove {
+            self.add.remove(member);
+            self.remove.insert(member.clone());
+        }
+    }
+
+    pub fn from(
+        name: &str,
+        contemporary: &RedisSetContents,
+        previous: Option<&RedisSetContents>
+    ) -> Option<RedisSetUpdate> {
+        let (add, remove) = match previous {
+            None => (contemporary.clone(), RedisSetContents::new()),
+            Some(previous) => {
+                let add: RedisSetContents = contemporary.difference(previous).cloned().collect();
+                let remove: RedisSetContents = previous.difference(contemporary).cloned().collect();
+                if add.is_empty() && remove.is_empty() {
+                    return None;
+                }
+                (add, remove)
+            }
+        };
+        Some(RedisSetUpdate {
+            name: name.to_string(),
+            add,
+            remove
+        })
+    }
+}
+
+/// Members added or re-scored (`upsert`) and members removed (`delete`)
+#[derive(Serialize, Clone)]
+pub struct RedisZsetUpdate {
+    name: String,
+    upsert: RedisZsetContents,
+    delete: HashSet<String>
+}
+
+impl RedisZsetUpdate {
+    fn merge(&mut self, next: &RedisZsetUpdate) {
+        for (member, score) in &next.upsert {
+            self.delete.remove(member);
+            self.upsert.insert(member.clone(), *score);
+        }
+        for member in &next.delete {
+            self.upsert.remove(member);
+            self.delete.insert(member.clone());
+        }
+    }
+
+    pub fn from(
+        name: &str,
+        contemporary: &RedisZsetContents,
+        previous: Option<&RedisZsetContents>
+    ) -> Option<RedisZsetUpdate> {
+        let (upsert, delete) = match previous {
+            None => (contemporary.clone(), HashSet::new()),
+            Some(previous) => {
+                let upsert: RedisZsetContents = contemporary.iter()
+                    .filter(|(member, score)| previous.get(*member) != Some(score))
+                    .map(|(member, score)| (member.clone(), *score))
+                    .collect();
+                let delete: HashSet<String> = previous.keys()
+                    .filter(|member| !contemporary.contains_key(*member))
+                    .cloned()
+                    .collect();
+                if upsert.is_empty() && delete.is_empty() {
+                    return None;
+                }
+                (upsert, delete)
+            }
+        };
+        Some(RedisZsetUpdate {
+            name: name.to_string(),
+            upsert,
+            delete
+        })
+    }
+}
+
+/// Entries appended since the last update, oldest first
+#[derive(Serialize, Clone)]
+pub struct RedisStreamUpdate {
+    name: String,
+    entries: Vec<StreamEntry>
+}
+
+impl RedisStreamUpdate {
+    pub fn from(
+        name: &str,
+        contemporary: &[StreamEntry],
+        previous: Option<&Vec<StreamEntry>>
+    ) -> Option<RedisStreamUpdate> {
+        let last_id = previous
+            .and_then(|previous| previous.last())
+            .map(|entry| stream_id(&entry.id));
+        let entries: Vec<StreamEntry> = contemporary.iter()
+            .filter(|entry| last_id.is_none_or(|last_id| stream_id(&entry.id) > last_id))
+            .cloned()
+            .collect();
+        if entries.is_empty() && previous.is_some() {
+            return None;
+        }
+        Some(RedisStreamUpdate {
+            name: name.to_string(),
+            entries
+        })
+    }
+}
+
+/// The change to a key since a client last saw it, tagged with the key's type.
+///
+/// A key that changed type is sent whole, as if seen for the first time.
+#[derive(Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RedisKeyUpdate {
+    /// the key was deleted, or never existed
+    None { name: String },
+    String(RedisStringUpdate),
+    Hash(RedisHashContentsUpdate),
+    List(RedisListUpdate),
+    Set(RedisSetUpdate),
+    Zset(RedisZsetUpdate),
+    Stream(RedisStreamUpdate)
+}
+
+impl RedisKeyUpdate {
+    /// Fold `next` into this update so that sending the one has the effect
+    /// of sending both in turn, `false` where that can't be done
+    pub fn merge(&mut self, next: &RedisKeyUpdate) -> bool {
+        match (self, next) {
+            (RedisKeyUpdate::None { .. }, RedisKeyUpdate::None { .. }) => (),
+            (RedisKeyUpdate::String(update), RedisKeyUpdate::String(next)) => {
+                update.value = next.value.clone();
+            },
+            (RedisKeyUpdate::Hash(update), RedisKeyUpdate::Hash(next)) => update.merge(next),
+            (RedisKeyUpdate::Set(update), RedisKeyUpdate::Set(next)) => update.merge(next),
+            (RedisKeyUpdate::Zset(update), RedisKeyUpdate::Zset(next)) => update.merge(next),
+            (RedisKeyUpdate::Stream(update), RedisKeyUpdate::Stream(next)) => {
+                update.entries.extend(next.entries.iter().cloned());
+            },
+            // splices don't combine, nor do changes of type
+            _ => return false
+        }
+        true
+    }
+
+    pub fn from(
+        name: &str,
+        contemporary: &RedisKeyContents,
+        previous: Option<&RedisKeyContents>
+    ) -> Option<RedisKeyUpdate> {
+        use RedisKeyContents as Contents;
+
+        match (contemporary, previous) {
+            (Contents::None, Some(Contents::None)) => None,
+            (Contents::None, _) => Some(RedisKeyUpdate::None { name: name.to_string() }),
+            (Contents::String(value), Some(Contents::String(previous))) =>
+                RedisStringUpdate::from(name, value, Some(previous)).map(RedisKeyUpdate::String),
+            (Contents::String(value), _) =>
+                RedisStringUpdate::from(name, value, None).map(RedisKeyUpdate::String),
+            (Contents::Hash(contents), Some(Contents::Hash(previous))) =>
+                RedisHashContentsUpdate::from(name, contents, Some(previous)).map(RedisKeyUpdate::Hash),
+            (Contents::Hash(contents), _) =>
+                RedisHashContentsUpdate::from(name, contents, None).map(RedisKeyUpdate::Hash),
+            (Contents::List(items), Some(Contents::List(previous))) =>
+                RedisListUpdate::from(name, items, Some(previous)).map(RedisKeyUpdate::List),
+            (Contents::List(items), _) =>
+                RedisListUpdate::from(name, items, None).map(RedisKeyUpdate::List),
+            (Contents::Set(members), Some(Contents::Set(previous))) =>
+                RedisSetUpdate::from(name, members, Some(previous)).map(RedisKeyUpdate::Set),
+            (Contents::Set(members), _) =>
+                RedisSetUpdate::from(name, members, None).map(RedisKeyUpdate::Set),
+            (Contents::Zset(members), Some(Contents::Zset(previous))) =>
+                RedisZsetUpdate::from(name, members, Some(previous)).map(RedisKeyUpdate::Zset),
+            (Contents::Zset(members), _) =>
+                RedisZsetUpdate::from(name, members, None).map(RedisKeyUpdate::Zset),
+            (Contents::Stream(entries), Some(Contents::Stream(previous))) =>
+                RedisStreamUpdate::from(name, entries, Some(previous)).map(RedisKeyUpdate::Stream),
+            (Contents::Stream(entries), _) =>
+                RedisStreamUpdate::from(name, entries, None).map(RedisKeyUpdate::Stream)
+        }
+    }
+}
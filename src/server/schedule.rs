@@ -0,0 +1,181 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::{Duration, Instant}
+};
+
+use crate::config::BrokerConfig;
+
+/// Token bucket limiting how many Redis reads the broker issues per second.
+///
+/// Holds up to one second's worth of requests, so short bursts (e.g. a new
+/// client requesting several hashes) are served without delay.
+struct RequestBudget {
+    /// requests per second, `None` when unlimited
+    rate: Option<f64>,
+    tokens: f64,
+    refilled: Instant
+}
+
+impl RequestBudget {
+    fn new(rate: f64) -> RequestBudget {
+        let rate = if rate > 0.0 { Some(rate) } else { None };
+        RequestBudget {
+            rate,
+            tokens: rate.map_or(0.0, |rate| rate.max(1.0)),
+            refilled: Instant::now()
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+            self.refilled = now;
+        }
+    }
+
+    /// When the next request may be issued
+    fn available_at(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        match self.rate {
+            Some(rate) if self.tokens < 1.0 => {
+                now + Duration::from_secs_f64((1.0 - self.tokens) / rate)
+            },
+            _ => now
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        match self.rate {
+            None => true,
+            Some(_) if self.tokens >= 1.0 => {
+                self.tokens -= 1.0;
+                true
+            },
+            Some(_) => false
+        }
+    }
+}
+
+/// Something the broker reads from Redis on a schedule
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RefreshTarget {
+    /// read a hash's contents
+    Hash(String),
+    /// scan for the keys matching a glob pattern
+    Pattern(String),
+    /// read the next page of a large hash for the query with this id
+    Query(usize)
+}
+
+/// Decides when each hash is next read from Redis, and each pattern next scanned.
+///
+/// A target is never refreshed more often than its minimum refresh interval,
+/// and all refreshes together stay within the request budget.
+pub struct RefreshSchedule {
+    min_interval: Duration,
+    intervals: HashMap<String, Duration>,
+    pattern_interval: Duration,
+    budget: RequestBudget,
+    /// authoritative due time of each scheduled target
+    due: HashMap<RefreshTarget, Instant>,
+    /// due times in order, entries not matching `due` are stale
+    queue: BinaryHeap<Reverse<(Instant, RefreshTarget)>>,
+    last_refresh: HashMap<RefreshTarget, Instant>
+}
+
+impl RefreshSchedule {
+    pub fn new(config: &BrokerConfig) -> RefreshSchedule {
+        RefreshSchedule {
+            min_interval: Duration::from_millis(config.min_refresh_interval_ms),
+            intervals: config.refresh_intervals_ms.iter().map(
+                |(hash, ms)| (hash.clone(), Duration::from_millis(*ms))
+            ).collect(),
+            pattern_interval: Duration::from_millis(config.pattern_rescan_interval_ms),
+            budget: RequestBudget::new(config.max_requests_per_second),
+            due: HashMap::new(),
+            queue: BinaryHeap::new(),
+            last_refresh: HashMap::new()
+        }
+    }
+
+    fn interval(&self, target: &RefreshTarget) -> Duration {
+        match target {
+            RefreshTarget::Hash(hash) => self.intervals.get(hash).copied().unwrap_or(self.min_interval),
+            RefreshTarget::Pattern(_) => self.pattern_interval,
+            // only ever continued
+            RefreshTarget::Query(_) => Duration::ZERO
+        }
+    }
+
+    fn schedule_at(&mut self, target: &RefreshTarget, at: Instant) {
+        if let Some(due) = self.due.get(target) {
+            if *due <= at {
+                return;
+            }
+        }
+        self.due.insert(target.clone(), at);
+        self.queue.push(Reverse((at, target.clone())));
+    }
+
+    /// Refresh `target` as soon as its minimum interval allows
+    pub fn schedule_now(&mut self, target: &RefreshTarget) {
+        let earliest = match self.last_refresh.get(target) {
+            Some(last) => *last + self.interval(target),
+            None => Instant::now()
+        };
+        self.schedule_at(target, earliest);
+    }
+
+    /// Continue refreshing `target` as soon as the budget allows, for
+    /// reads spread over several requests
+    pub fn schedule_continuation(&mut self, target: &RefreshTarget) {
+        self.schedule_at(target, Instant::now());
+    }
+
+    /// Refresh `target` again one interval after its last refresh
+    pub fn schedule_next(&mut self, target: &RefreshTarget) {
+        let last = self.last_refresh.get(target).copied().unwrap_or_else(Instant::now);
+        self.schedule_at(target, last + self.interval(target));
+    }
+
+    pub fn unschedule(&mut self, target: &RefreshTarget) {
+        self.due.remove(target);
+        self.last_refresh.remove(target);
+    }
+
+    fn discard_stale(&mut self) {
+        while let Some(Reverse((at, target))) = self.queue.peek() {
+            if self.due.get(target) == Some(at) {
+                break;
+            }
+            self.queue.pop();
+        }
+    }
+
+    /// When the broker next has to wake up, `None` if nothing is scheduled
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.discard_stale();
+        let Reverse((at, _)) = self.queue.peek()?;
+        let at = *at;
+        Some(at.max(self.budget.available_at(Instant::now())))
+    }
+
+    /// Take the next target due for refreshing by `now`, if the budget allows
+    pub fn pop_due(&mut self, now: Instant) -> Option<RefreshTarget> {
+        self.discard_stale();
+        match self.queue.peek() {
+            Some(Reverse((at, _))) if *at <= now => (),
+            _ => return None
+        }
+        if !self.budget.try_take(now) {
+            return None;
+        }
+        let Reverse((_, target)) = self.queue.pop()?;
+        self.due.remove(&target);
+        self.last_refresh.insert(target.clone(), Instant::now());
+        Some(target)
+    }
+}
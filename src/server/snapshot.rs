@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+
+use crate::server::{
+    redis_hash::{RedisHashContents, RedisHashContentsUpdate},
+    redis_key::{RedisKeyContents, RedisKeyUpdate}
+};
+
+/// The broker's one copy of a key, shared by every client it is sent to.
+///
+/// Each change bumps the version, so a client need only remember which
+/// version it was last sent for the change to it to be worked out once for
+/// everybody.
+pub struct KeySnapshot {
+    pub version: u64,
+    pub contents: RedisKeyContents,
+
+    /// The fields a hash was last read with, `None` if it was read whole
+    pub fields: Option<HashSet<String>>
+}
+
+impl KeySnapshot {
+    pub fn new(contents: RedisKeyContents) -> KeySnapshot {
+        KeySnapshot {
+            version: 1,
+            contents,
+            fields: None
+        }
+    }
+
+    /// Whether the snapshot was read with every field in `wanted`, `None`
+    /// being all of them
+    pub fn has_fields(&self, wanted: Option<&HashSet<String>>) -> bool {
+        match (&self.fields, wanted) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(fields), Some(wanted)) => wanted.is_subset(fields)
+        }
+    }
+
+    /// Take on what `name` now holds, returning the change from the
+    /// previous version if there is one
+    pub fn replace(&mut self, name: &str, contents: RedisKeyContents) -> Option<RedisKeyUpdate> {
+        let update = RedisKeyUpdate::from(name, &contents, Some(&self.contents))?;
+        self.contents = contents;
+        self.version += 1;
+        Some(update)
+    }
+
+    /// Change a hash in place by the update `diff` finds, for hashes too
+    /// large to be read, and copied, whole
+    pub fn patch_hash(
+        &mut self,
+        name: &str,
+        diff: impl FnOnce(&RedisHashContents) -> Option<RedisHashContentsUpdate>
+    ) -> Option<RedisKeyUpdate> {
+        // scans read every field
+        self.fields = None;
+        let retyped = !matches!(self.contents, RedisKeyContents::Hash(_));
+        if retyped {
+            self.contents = RedisKeyContents::Hash(RedisHashContents::new());
+        }
+        let RedisKeyContents::Hash(fields) = &mut self.contents else {
+            unreachable!();
+        };
+        let update = match diff(fields) {
+            Some(update) => update,
+            // a hash now, even if nothing of it was read yet
+            None if retyped => RedisHashContentsUpdate::from(name, fields, None)?,
+            None => return None
+        };
+        update.apply(fields);
+        self.version += 1;
+        Some(RedisKeyUpdate::Hash(update))
+    }
+
+    /// The whole key, for clients holding no version or an outdated one
+    pub fn whole(&self, name: &str) -> Option<RedisKeyUpdate> {
+        RedisKeyUpdate::from(name, &self.contents, None)
+    }
+}
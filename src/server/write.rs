@@ -0,0 +1,191 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt
+};
+
+use crate::{server::connection::Connection, session::client_action::Expectation};
+
+/// Times a checked write is tried while others keep changing the hash,
+/// the broker serving nobody else meanwhile
+const MAX_WRITE_ATTEMPTS: usize = 3;
+
+/// Why a client's write was not carried out
+pub enum WriteError {
+    /// the write makes no sense for the hash as it is
+    Invalid(String),
+    /// fields did not hold what the client expected, these are their current values
+    Conflict(Expectation),
+    /// the hash to change does not exist
+    MissingKey,
+    /// the client may not write to the hash
+    Forbidden,
+    /// the hash kept changing while being checked, the write may be retried
+    Contended,
+    /// Redis is unreachable
+    Unavailable,
+    Redis(redis::RedisError)
+}
+
+impl From<redis::RedisError> for WriteError {
+    fn from(err: redis::RedisError) -> Self {
+        WriteError::Redis(err)
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Invalid(reason) => f.write_str(reason),
+            WriteError::Conflict(_) => f.write_str("hash changed since last seen"),
+            WriteError::MissingKey => f.write_str("no such hash"),
+            WriteError::Forbidden => f.write_str("not allowed to write to this hash"),
+            WriteError::Contended => f.write_str("hash kept changing, try again"),
+            WriteError::Unavailable => f.write_str("Redis is unreachable"),
+            WriteError::Redis(err) => err.fmt(f)
+        }
+    }
+}
+
+fn require_fields(count: usize) -> Result<(), WriteError> {
+    if count == 0 {
+        return Err(WriteError::Invalid("no fields given".to_string()));
+    }
+    Ok(())
+}
+
+/// Read `fields` of the WATCHed hash, the `expected` ones first, and see
+/// that they hold what `expect` says and that `check` accepts the rest
+fn check_fields(
+    redis_connection: &mut Connection,
+    hash: &str,
+    expect: Option<&Expectation>,
+    expected: &[&String],
+    fields: &[&String],
+    check: &impl Fn(&[Option<String>]) -> Result<(), WriteError>
+) -> Result<(), WriteError> {
+    let values: Vec<Option<String>> = if fields.is_empty() {
+        Vec::new()
+    } else {
+        redis::cmd("HMGET").arg(hash).arg(fields).query(redis_connection)?
+    };
+    let (seen, values) = values.split_at(expected.len());
+
+    match expect {
+        Some(expect) if expected.iter().zip(seen).any(|(field, value)| expect[*field] != *value) => {
+            Err(WriteError::Conflict(
+                expected.iter().map(|field| (*field).clone()).zip(seen.iter().cloned()).collect()
+            ))
+        },
+        _ => check(values)
+    }
+}
+
+/// Run the commands `queue` adds as one transaction, provided the fields in
+/// `expect` hold the values given and `check` accepts the values of the
+/// fields in `read`.
+///
+/// The hash is WATCHed while checking, so the commands only run against
+/// what was checked; if it changes in between everything is checked again,
+/// up to `MAX_WRITE_ATTEMPTS` times.
+fn checked_write(
+    redis_connection: &mut Connection,
+    hash: &str,
+    expect: Option<&Expectation>,
+    read: &[&String],
+    check: impl Fn(&[Option<String>]) -> Result<(), WriteError>,
+    queue: impl Fn(&mut redis::Pipeline)
+) -> Result<(), WriteError> {
+    if expect.is_none() && read.is_empty() {
+        let mut pipeline = redis::pipe();
+        queue(&mut pipeline);
+        pipeline.query::<()>(redis_connection)?;
+        return Ok(());
+    }
+
+    let expected: Vec<&String> = expect.map(|expect| expect.keys().collect()).unwrap_or_default();
+    let fields: Vec<&String> = expected.iter().chain(read).copied().collect();
+    for _ in 0..MAX_WRITE_ATTEMPTS {
+        redis::cmd("WATCH").arg(hash).query::<()>(redis_connection)?;
+        let checked = check_fields(redis_connection, hash, expect, &expected, &fields, &check);
+        if let Err(err) = checked {
+            // the connection is shared, a lingering WATCH would abort the next MULTI
+            let _ = redis::cmd("UNWATCH").query::<()>(redis_connection);
+            return Err(err);
+        }
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        queue(&mut pipeline);
+        // `None` when the hash changed after WATCH
+        let applied: Option<()> = pipeline.query(redis_connection)?;
+        if applied.is_some() {
+            return Ok(());
+        }
+    }
+    Err(WriteError::Contended)
+}
+
+pub fn hset(
+    redis_connection: &mut Connection,
+    hash: &str,
+    fields: &HashMap<String, String>,
+    expect: Option<&Expectation>
+) -> Result<(), WriteError> {
+    require_fields(fields.len())?;
+    checked_write(redis_connection, hash, expect, &[], |_| Ok(()), |pipeline| {
+        let command = pipeline.cmd("HSET").arg(hash);
+        for (field, value) in fields {
+            command.arg(field).arg(value);
+        }
+        command.ignore();
+    })
+}
+
+pub fn hdel(
+    redis_connection: &mut Connection,
+    hash: &str,
+    fields: &HashSet<String>,
+    expect: Option<&Expectation>
+) -> Result<(), WriteError> {
+    require_fields(fields.len())?;
+    let exists: bool = redis::cmd("EXISTS").arg(hash).query(redis_connection)?;
+    if !exists {
+        return Err(WriteError::MissingKey);
+    }
+    checked_write(redis_connection, hash, expect, &[], |_| Ok(()), |pipeline| {
+        pipeline.cmd("HDEL").arg(hash).arg(fields.iter().collect::<Vec<_>>()).ignore();
+    })
+}
+
+/// Increment every field or, if any of them can't be, none.
+///
+/// EXEC does not roll back after a failed HINCRBY, so the fields are
+/// checked first.
+pub fn hincrby(
+    redis_connection: &mut Connection,
+    hash: &str,
+    increments: &HashMap<String, i64>,
+    expect: Option<&Expectation>
+) -> Result<(), WriteError> {
+    require_fields(increments.len())?;
+    let fields: Vec<&String> = increments.keys().collect();
+    let check = |values: &[Option<String>]| {
+        for (field, value) in fields.iter().zip(values) {
+            let current = match value {
+                None => Some(0),
+                Some(value) => value.parse::<i64>().ok()
+            };
+            if current.and_then(|current| current.checked_add(increments[*field])).is_none() {
+                return Err(WriteError::Invalid(
+                    format!("{field} is not an integer or would overflow")
+                ));
+            }
+        }
+        Ok(())
+    };
+    checked_write(redis_connection, hash, expect, &fields, check, |pipeline| {
+        for field in &fields {
+            pipeline.cmd("HINCRBY").arg(hash).arg(*field).arg(increments[*field]).ignore();
+        }
+    })
+}
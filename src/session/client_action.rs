@@ -1,72 +1,94 @@
-use std::{collections::HashSet, fmt};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration
+};
 
-use serde::{Deserialize, Serialize, Serializer, ser::SerializeMap, Deserializer, de::{Visitor, MapAccess}};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Hash, Serialize, Deserialize)]
-#[serde(rename_all="lowercase")]
-pub enum ClientActions {
-    Drop,
-    Request
-}
+use crate::session::protocol::Hello;
 
-#[derive(Debug)]
-pub struct ClientAction {
-    pub action: ClientActions,
-    pub hash_names: HashSet<String>
-}
+/// Chosen by the client to match replies to its write actions, echoed as is
+pub type RequestId = serde_json::Value;
 
-impl Serialize for ClientAction {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut map = serializer.serialize_map(Some(1))?;
-        
-        map.serialize_entry(
-            &self.action,
-            &self.hash_names
-        )?;
-        map.end()
-    }
+/// Field values a client last saw, `None` for a field that was absent
+pub type Expectation = HashMap<String, Option<String>>;
+
+/// A write to the fields of one hash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HashWrite<T> {
+    pub id: RequestId,
+    pub hash: String,
+    pub fields: T,
+    /// only write if these fields still hold these values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect: Option<Expectation>
 }
 
-impl<'de> Deserialize<'de> for ClientAction
-{
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        struct ClientActionVisitor;
+/// A key to receive, by name alone or with options, e.g. `"test:1"` or
+/// `{"key": "test:1", "fields": ["status", "cpu_*"], "max_rate": 2}`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeyRequest {
+    Key(String),
+    Subscription(Subscription)
+}
 
-        impl<'de> Visitor<'de> for ClientActionVisitor {
-            type Value = ClientAction;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    pub key: String,
+    /// of a hash, only the fields given by name or glob pattern
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<String>>,
+    /// at most this many updates a second, changes in between are merged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rate: Option<f64>,
+    /// at least this long between updates, changes in between are merged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_interval_ms: Option<u64>
+}
 
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("single entry map<ClientAction, HashSet<String>>")
-            }
+/// Longest a subscription may ask to wait between two updates
+const MAX_MIN_INTERVAL: Duration = Duration::from_secs(3600);
 
-            fn visit_map<V>(self, mut map: V) -> Result<ClientAction, V::Error>
-            where
-                V: MapAccess<'de>,
-            {
-                let mut action: Option<ClientActions> = None;
-                let mut hash_names: Option<HashSet<String>> = None;
-                while let Some(action_key) = map.next_key()? {
-                    if action.is_some() {
-                        return Err(serde::de::Error::duplicate_field("action"));
-                    }
-                    action = Some(action_key);
-                    hash_names = Some(map.next_value()?);
-                }
-                let action = action.ok_or_else(|| serde::de::Error::missing_field("action"))?;
-                let hash_names = hash_names.unwrap_or(HashSet::new());
-                Ok(ClientAction {
-                    action: action,
-                    hash_names: hash_names
-                })
-            }
+impl Subscription {
+    /// Shortest time allowed between two updates, if any, or the reason the
+    /// one asked for can't be used
+    pub fn min_interval(&self) -> Result<Option<Duration>, String> {
+        let from_rate = self.max_rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::try_from_secs_f64(1.0 / rate).unwrap_or(Duration::MAX));
+        let interval = self.min_interval_ms.map(Duration::from_millis);
+        match from_rate.max(interval).filter(|interval| !interval.is_zero()) {
+            Some(interval) if interval > MAX_MIN_INTERVAL => Err(format!(
+                "updates can't be spaced more than {}s apart", MAX_MIN_INTERVAL.as_secs()
+            )),
+            interval => Ok(interval)
         }
-
-        deserializer.deserialize_map(ClientActionVisitor)
     }
-}
\ No newline at end of file
+}
+
+/// What a client can ask for, sent as a single entry map, e.g.
+/// `{"request": ["test:1"]}` or
+/// `{"hset": {"id": 1, "hash": "test:1", "fields": {"a": "x"}}}`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum ClientActions {
+    /// negotiate the protocol, only valid as the first message
+    Hello(Hello),
+    Drop(HashSet<String>),
+    Request(Vec<KeyRequest>),
+    SubscribePattern(HashSet<String>),
+    UnsubscribePattern(HashSet<String>),
+    /// set fields to values
+    Hset(HashWrite<HashMap<String, String>>),
+    /// delete fields
+    Hdel(HashWrite<HashSet<String>>),
+    /// increment integer fields by the given amounts
+    Hincrby(HashWrite<HashMap<String, i64>>)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientAction {
+    #[serde(flatten)]
+    pub action: ClientActions
+}
@@ -1,17 +1,42 @@
 pub mod client_action;
+pub mod protocol;
+pub mod sse;
 
-use std::{time::{Duration, Instant}, sync::mpsc::Sender};
+use std::{
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::{Duration, Instant}
+};
 
 use actix::prelude::*;
+use actix_http::ws::Item;
+use actix_web::web::{Bytes, BytesMut};
 use actix_web_actors::ws;
+use rand::{distributions::Alphanumeric, Rng};
 
 use crate::{
+    auth::Identity,
     server::{
+        BrokerSender,
         SessionMessages,
         SessionMessage,
-        client::JsonMessage
+        Shutdown,
+        client::{EncodedMessage, ErrorCode, ErrorMessage, ServerMessage, SessionLink}
     },
-    session::client_action::ClientAction
+    session::{
+        client_action::{ClientAction, ClientActions},
+        protocol::{
+            ACTIONS,
+            COMPRESSIONS,
+            Compression,
+            ENCODINGS,
+            Encoding,
+            Hello,
+            LEGACY_PROTOCOL_VERSION,
+            PROTOCOL_VERSION,
+            Resume,
+            Welcome
+        }
+    }
 };
 
 /// How often heartbeat pings are sent
@@ -20,6 +45,27 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Length of the random token a session can be resumed with
+const RESUME_TOKEN_LENGTH: usize = 32;
+
+/// Largest message a client may send split into several frames
+const MAX_FRAGMENTED_SIZE: usize = 1 << 20;
+
+/// A random token a session can later be resumed with
+fn new_resume_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RESUME_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// The frames so far of a message the client sends in several
+pub struct Fragments {
+    binary: bool,
+    bytes: BytesMut
+}
+
 pub struct WsChatSession {
     /// unique session id
     pub id: usize,
@@ -29,10 +75,51 @@ pub struct WsChatSession {
     pub hb: Instant,
 
     /// Sender to the RedisHashBroker
-    pub tx: Sender<SessionMessage>,
+    pub tx: BrokerSender,
+
+    /// Protocol version spoken, `None` until the client's first message
+    pub protocol: Option<u32>,
+
+    /// Encoding of the messages after the welcome, JSON until negotiated
+    pub encoding: Encoding,
+
+    /// Compression of the messages after the welcome, if negotiated
+    pub compression: Option<Compression>,
+
+    /// Messages longer than this are sent in several frames, 0 for never
+    pub chunk_size: usize,
+
+    /// A message the client is part way through sending
+    pub fragments: Option<Fragments>,
+
+    /// Messages the broker has sent that are yet to be handled
+    pub queued: Arc<AtomicUsize>,
+
+    /// Who the client authenticated as
+    pub identity: Identity,
 }
 
 impl WsChatSession {
+    pub fn new(
+        id: usize,
+        tx: BrokerSender,
+        chunk_size: usize,
+        identity: Identity
+    ) -> WsChatSession {
+        WsChatSession {
+            id,
+            hb: Instant::now(),
+            tx,
+            protocol: None,
+            encoding: Encoding::Json,
+            compression: None,
+            chunk_size,
+            fragments: None,
+            queued: Arc::new(AtomicUsize::new(0)),
+            identity
+        }
+    }
+
     /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
     ///
     /// also this method checks heartbeats from client
@@ -56,46 +143,240 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Register with RedisHashBroker, speaking `protocol` in `encoding` from now on
+    fn connect(
+        &mut self,
+        protocol: u32,
+        encoding: Encoding,
+        resume_token: Option<String>,
+        resume: Option<Resume>,
+        ctx: &mut ws::WebsocketContext<Self>
+    ) {
+        self.protocol = Some(protocol);
+        self.encoding = encoding;
+        self.tx.send(
+            SessionMessage {
+                id: self.id,
+                message: SessionMessages::Connect {
+                    link: SessionLink {
+                        session: ctx.address().recipient(),
+                        protocol,
+                        encoding,
+                        queued: self.queued.clone()
+                    },
+                    identity: self.identity.clone(),
+                    resume_token,
+                    resume
+                },
+            }.into()
+        );
+    }
+
+    fn send(&self, message: EncodedMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let message = match (self.compression, message) {
+            (Some(compression), EncodedMessage::Text(text)) => {
+                EncodedMessage::Binary(compression.compress(text.as_bytes()))
+            },
+            (Some(compression), EncodedMessage::Binary(bytes)) => {
+                EncodedMessage::Binary(compression.compress(&bytes))
+            },
+            (None, message) => message
+        };
+        let fits = |length: usize| self.chunk_size == 0 || length <= self.chunk_size;
+        match message {
+            EncodedMessage::Text(text) if fits(text.len()) => ctx.text(text),
+            EncodedMessage::Binary(bytes) if fits(bytes.len()) => ctx.binary(bytes),
+            EncodedMessage::Text(text) => self.send_chunked(Bytes::from(text), false, ctx),
+            EncodedMessage::Binary(bytes) => self.send_chunked(Bytes::from(bytes), true, ctx)
+        }
+    }
+
+    /// Send a message as continuation frames of `chunk_size` bytes, which
+    /// the client's websocket puts back together
+    fn send_chunked(&self, bytes: Bytes, binary: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        let count = bytes.len().div_ceil(self.chunk_size);
+        for index in 0..count {
+            let chunk = bytes.slice(
+                index * self.chunk_size..bytes.len().min((index + 1) * self.chunk_size)
+            );
+            let item = match index {
+                0 if binary => Item::FirstBinary(chunk),
+                0 => Item::FirstText(chunk),
+                _ if index == count - 1 => Item::Last(chunk),
+                _ => Item::Continue(chunk)
+            };
+            ctx.write_raw(ws::Message::Continuation(item));
+        }
+    }
+
+    fn send_error(&self, code: ErrorCode, message: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let error = ServerMessage::Error(ErrorMessage::new(code, message));
+        let protocol = self.protocol.unwrap_or(LEGACY_PROTOCOL_VERSION);
+        self.send(EncodedMessage::encode(&error, protocol, self.encoding), ctx);
+    }
+
+    /// Settle the protocol with a client that said hello, refusing it if
+    /// there is nothing both sides speak
+    fn hello(&mut self, hello: Hello, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.protocol.is_some() {
+            self.send_error(ErrorCode::InvalidAction, "hello must be the first message".to_string(), ctx);
+            return;
+        }
+        let Some((protocol, encoding)) = hello.negotiate() else {
+            let reason = format!(
+                "protocol {} with encodings {:?} is not supported",
+                hello.protocol,
+                hello.encodings
+            );
+            // anyone saying hello understands the envelope
+            let error = ServerMessage::Error(
+                ErrorMessage::new(ErrorCode::UnsupportedProtocol, reason.clone())
+            );
+            self.send(EncodedMessage::encode(&error, PROTOCOL_VERSION, Encoding::Json), ctx);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Protocol,
+                description: Some(reason)
+            }));
+            ctx.stop();
+            return;
+        };
+
+        let resume_token = new_resume_token();
+        let welcome = ServerMessage::Welcome(Welcome {
+            protocol,
+            server_version: env!("CARGO_PKG_VERSION"),
+            session_id: self.id,
+            actions: ACTIONS,
+            encodings: ENCODINGS,
+            encoding,
+            compressions: COMPRESSIONS,
+            compression: hello.compression(),
+            resume_token: resume_token.clone()
+        });
+        // in JSON like the hello, the client switches encoding after reading it
+        self.send(EncodedMessage::encode(&welcome, protocol, Encoding::Json), ctx);
+        self.compression = hello.compression();
+        self.connect(protocol, encoding, Some(resume_token), hello.resume, ctx);
+    }
+
+    /// Read a message from the client, JSON if sent as text and in the
+    /// session's encoding if binary
+    fn receive_frame(&mut self, binary: bool, bytes: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        let action = if binary {
+            self.encoding.decode(bytes)
+        } else {
+            // JSON is understood whatever the encoding
+            serde_json::from_slice(bytes).map_err(|err| err.to_string())
+        };
+        self.receive(action, ctx);
+    }
+
+    /// Gather a message sent in several frames, reading it once complete
+    fn receive_fragment(&mut self, item: Item, ctx: &mut ws::WebsocketContext<Self>) {
+        let (binary, bytes, last) = match item {
+            Item::FirstText(bytes) => (Some(false), bytes, false),
+            Item::FirstBinary(bytes) => (Some(true), bytes, false),
+            Item::Continue(bytes) => (None, bytes, false),
+            Item::Last(bytes) => (None, bytes, true)
+        };
+        if let Some(binary) = binary {
+            self.fragments = Some(Fragments { binary, bytes: BytesMut::new() });
+        }
+        let Some(fragments) = self.fragments.as_mut() else {
+            // a continuation of nothing
+            ctx.stop();
+            return;
+        };
+        if fragments.bytes.len() + bytes.len() > MAX_FRAGMENTED_SIZE {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Size,
+                description: Some(format!("messages are limited to {MAX_FRAGMENTED_SIZE} bytes"))
+            }));
+            ctx.stop();
+            return;
+        }
+        fragments.bytes.extend_from_slice(&bytes);
+        if last {
+            let Fragments { binary, bytes } = self.fragments.take().unwrap();
+            self.receive_frame(binary, &bytes, ctx);
+        }
+    }
+
+    /// Act on a message from the client, `Err` if it could not be read
+    fn receive(&mut self, action: Result<ClientAction, String>, ctx: &mut ws::WebsocketContext<Self>) {
+        match action {
+            Ok(ClientAction { action: ClientActions::Hello(hello) }) => {
+                self.hello(hello, ctx);
+            },
+            Ok(action) => {
+                if self.protocol.is_none() {
+                    // a client that doesn't say hello predates it
+                    self.connect(LEGACY_PROTOCOL_VERSION, Encoding::Json, None, None, ctx);
+                }
+                self.tx.send(
+                    SessionMessage {
+                        id: self.id,
+                        message: SessionMessages::Action(action)
+                    }.into()
+                );
+            },
+            Err(err) => {
+                self.send_error(ErrorCode::InvalidAction, err, ctx);
+            }
+        }
+    }
 }
 
 impl Actor for WsChatSession {
     type Context = ws::WebsocketContext<Self>;
 
     /// Method is called on actor start.
-    /// We register ws session with RedisHashBroker
+    /// The session registers with RedisHashBroker once the client's first
+    /// message shows which protocol it speaks
     fn started(&mut self, ctx: &mut Self::Context) {
         // we'll start heartbeat process on session start.
         self.hb(ctx);
-
-        // Send self registration details to RedisHashBroker
-        let _ = self.tx.send(
+        self.tx.send(
             SessionMessage {
                 id: self.id,
-                message: SessionMessages::Connect(
-                    ctx.address().recipient()
-                ),
-            }
+                message: SessionMessages::Open { closer: ctx.address().recipient() }
+            }.into()
         );
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // notify server
-        let _ = self.tx.send(
+        self.tx.send(
             SessionMessage {
                 id: self.id,
                 message: SessionMessages::Disconnect,
-            }
+            }.into()
         );
         Running::Stop
     }
 }
 
-/// Handle JsonMessage from RedisHashBroker
-impl Handler<JsonMessage> for WsChatSession {
+/// Handle EncodedMessage from RedisHashBroker
+impl Handler<EncodedMessage> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, message: EncodedMessage, ctx: &mut Self::Context) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        self.send(message, ctx);
+    }
+}
+
+/// Close the socket as the server shuts down, telling the client when to come back
+impl Handler<Shutdown> for WsChatSession {
     type Result = ();
 
-    fn handle(&mut self, json: JsonMessage, ctx: &mut Self::Context) {
-        ctx.text(json.string);
+    fn handle(&mut self, shutdown: Shutdown, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Restart,
+            description: Some(shutdown.reason())
+        }));
+        ctx.stop();
     }
 }
 
@@ -121,27 +402,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 self.hb = Instant::now();
             }
             ws::Message::Text(text) => {
-                match serde_json::from_str::<ClientAction>(&text) {
-                    Ok(action) => {
-                        let _ = self.tx.send(
-                            SessionMessage {
-                                id: self.id,
-                                message: SessionMessages::Action(action)
-                            }
-                        );
-                    },
-                    Err(err) => {
-                        ctx.text(format!("!!! {:}", err));
-                    }
-                }
+                self.receive_frame(false, text.as_bytes(), ctx);
+            }
+            ws::Message::Binary(bytes) => {
+                self.receive_frame(true, &bytes, ctx);
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
             }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
+            ws::Message::Continuation(item) => {
+                self.receive_fragment(item, ctx);
             }
             ws::Message::Nop => (),
         }
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, io::Write};
+
+use flate2::{write::DeflateEncoder, Compression as Level};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Newest protocol version the server speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The original protocol: the same messages without the `type` envelope,
+/// spoken to clients that never say hello
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Message encodings the server can speak, preferred first
+pub const ENCODINGS: &[Encoding] = &[Encoding::Json, Encoding::Msgpack, Encoding::Cbor];
+
+/// Compressions the server can apply to its messages, preferred first
+pub const COMPRESSIONS: &[Compression] = &[Compression::Deflate];
+
+/// Actions the server understands, as named on the wire
+pub const ACTIONS: &[&str] = &[
+    "hello",
+    "request",
+    "drop",
+    "subscribe_pattern",
+    "unsubscribe_pattern",
+    "hset",
+    "hdel",
+    "hincrby"
+];
+
+/// How messages are put on the wire after the welcome
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// text frames holding JSON
+    Json,
+    /// binary frames holding MessagePack
+    Msgpack,
+    /// binary frames holding CBOR
+    Cbor
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Msgpack => "msgpack",
+            Encoding::Cbor => "cbor"
+        }
+    }
+
+    /// Read a binary frame, the reason it can't be read otherwise
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => Err("binary frames need a binary encoding, chosen in hello".to_string()),
+            Encoding::Msgpack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|err| err.to_string())
+        }
+    }
+}
+
+/// How messages after the welcome are compressed, if at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// raw deflate (RFC 1951), sent in binary frames
+    Deflate
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate"
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+}
+
+/// First message of a client that negotiates, e.g. `{"hello": {"protocol": 2}}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    /// newest protocol version the client speaks
+    pub protocol: u32,
+    /// encodings the client can use, preferred first; JSON if left out
+    #[serde(default)]
+    pub encodings: Vec<String>,
+    /// compressions the client can undo, preferred first; none if left out
+    #[serde(default)]
+    pub compressions: Vec<String>,
+    /// take over the subscriptions of an earlier session
+    #[serde(default)]
+    pub resume: Option<Resume>
+}
+
+/// What a reconnecting client saw before its connection dropped
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resume {
+    /// `resume_token` of the earlier session's welcome
+    pub token: String,
+    /// `seq` of the last update received for each key
+    #[serde(default)]
+    pub seqs: HashMap<String, u64>
+}
+
+/// The server's answer to a hello
+#[derive(Serialize)]
+pub struct Welcome {
+    /// version both sides speak from now on
+    pub protocol: u32,
+    pub server_version: &'static str,
+    pub session_id: usize,
+    pub actions: &'static [&'static str],
+    pub encodings: &'static [Encoding],
+    /// encoding chosen for this session
+    pub encoding: Encoding,
+    pub compressions: &'static [Compression],
+    /// compression chosen for this session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// hand back in a later hello to resume this session
+    pub resume_token: String
+}
+
+impl Hello {
+    /// The protocol version and encoding to use, `None` if the client
+    /// speaks nothing the server does
+    pub fn negotiate(&self) -> Option<(u32, Encoding)> {
+        if self.protocol < LEGACY_PROTOCOL_VERSION {
+            return None;
+        }
+        let encoding = if self.encodings.is_empty() {
+            ENCODINGS[0]
+        } else {
+            *self.encodings.iter().find_map(
+                |wanted| ENCODINGS.iter().find(|encoding| encoding.name() == wanted)
+            )?
+        };
+        Some((self.protocol.min(PROTOCOL_VERSION), encoding))
+    }
+
+    /// The compression to use, `None` leaves messages uncompressed
+    pub fn compression(&self) -> Option<Compression> {
+        self.compressions.iter().find_map(
+            |wanted| COMPRESSIONS.iter().find(|compression| compression.name() == wanted)
+        ).copied()
+    }
+}
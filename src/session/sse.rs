@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    task::{Context as TaskContext, Poll}
+};
+
+use actix::prelude::*;
+use actix_web::{web::Bytes, Error};
+use futures_core::Stream;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    auth::Identity,
+    server::{
+        BrokerSender,
+        SessionMessages,
+        SessionMessage,
+        Shutdown,
+        client::{EncodedMessage, SessionLink}
+    },
+    session::{
+        HEARTBEAT_INTERVAL,
+        client_action::{ClientAction, ClientActions, KeyRequest},
+        new_resume_token,
+        protocol::{Encoding, PROTOCOL_VERSION, Resume}
+    }
+};
+
+/// Part of the response body, `from_broker` if it holds a message the
+/// broker counted as queued
+struct Chunk {
+    bytes: Bytes,
+    from_broker: bool
+}
+
+/// The body of a server-sent events response, written to by its session.
+///
+/// A message only stops counting as queued once the body yields it, which
+/// is as fast as the client reads, so the broker holds updates back from a
+/// slow reader rather than piling them up here.
+pub struct SseStream {
+    chunks: UnboundedReceiver<Chunk>,
+    queued: Arc<AtomicUsize>
+}
+
+impl Stream for SseStream {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.chunks.poll_recv(cx).map(|chunk| chunk.map(|chunk| {
+            if chunk.from_broker {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+            }
+            Ok(chunk.bytes)
+        }))
+    }
+}
+
+/// A client streaming hashes as server-sent events, for those that can't
+/// open a websocket. It only listens, so its hashes are requested up front.
+///
+/// Each update's event id carries the session's resume token and the
+/// `seq` of every key so far, which the browser sends back as
+/// `Last-Event-ID` when it reconnects.
+pub struct SseSession {
+    /// unique session id
+    pub id: usize,
+
+    /// Sender to the RedisHashBroker
+    pub tx: BrokerSender,
+
+    /// Where events go, the response body
+    events: UnboundedSender<Chunk>,
+
+    /// Hashes to stream
+    hashes: Vec<String>,
+
+    resume_token: String,
+
+    /// An earlier session to resume, until connected
+    resume: Option<Resume>,
+
+    /// `seq` of the last update sent of each key
+    seqs: HashMap<String, u64>,
+
+    /// Messages the broker has sent that are yet to be handled
+    queued: Arc<AtomicUsize>,
+
+    /// Who the client authenticated as
+    identity: Identity
+}
+
+impl SseSession {
+    /// A session streaming `hashes`, resuming the one `last_event_id` came
+    /// from if any, and the body its events are written to
+    pub fn new(
+        id: usize,
+        tx: BrokerSender,
+        hashes: Vec<String>,
+        last_event_id: Option<&str>,
+        identity: Identity
+    ) -> (SseSession, SseStream) {
+        let (events, chunks) = unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let resume: Option<Resume> = last_event_id.and_then(|id| serde_json::from_str(id).ok());
+        let session = SseSession {
+            id,
+            tx,
+            events,
+            hashes,
+            resume_token: new_resume_token(),
+            seqs: resume.as_ref().map(|resume| resume.seqs.clone()).unwrap_or_default(),
+            resume,
+            queued: queued.clone(),
+            identity
+        };
+        (session, SseStream { chunks, queued })
+    }
+
+    /// Write `event` to the response, stopping once the client has gone
+    fn write(&self, event: String, from_broker: bool, ctx: &mut Context<Self>) {
+        if self.events.send(Chunk { bytes: Bytes::from(event), from_broker }).is_err() {
+            ctx.stop();
+        }
+    }
+
+    /// Comment lines keep proxies from timing out an idle stream, and
+    /// show when the client has gone
+    fn hb(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            act.write(": heartbeat\n\n".to_string(), false, ctx);
+        });
+    }
+
+    /// Note what `message` tells the client of its keys, returning the
+    /// event id to resume after it if it's an update
+    fn track(&mut self, message: &Value) -> Option<String> {
+        match message["type"].as_str() {
+            Some("update") => {
+                let name = message["name"].as_str()?;
+                let seq = message["seq"].as_u64()?;
+                self.seqs.insert(name.to_string(), seq);
+                Some(json!({ "token": self.resume_token, "seqs": self.seqs }).to_string())
+            },
+            Some("resume") => {
+                if message["resumed"].as_bool() != Some(true) {
+                    self.seqs.clear();
+                }
+                if let Some(dropped) = message["dropped"].as_array() {
+                    for key in dropped.iter().filter_map(Value::as_str) {
+                        self.seqs.remove(key);
+                    }
+                }
+                None
+            },
+            _ => None
+        }
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    /// Register with RedisHashBroker and request the hashes at once
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.hb(ctx);
+        self.tx.send(
+            SessionMessage {
+                id: self.id,
+                message: SessionMessages::Open { closer: ctx.address().recipient() }
+            }.into()
+        );
+        self.tx.send(
+            SessionMessage {
+                id: self.id,
+                message: SessionMessages::Connect {
+                    link: SessionLink {
+                        session: ctx.address().recipient(),
+                        protocol: PROTOCOL_VERSION,
+                        encoding: Encoding::Json,
+                        queued: self.queued.clone()
+                    },
+                    identity: self.identity.clone(),
+                    resume_token: Some(self.resume_token.clone()),
+                    resume: self.resume.take()
+                },
+            }.into()
+        );
+        let requests = self.hashes.iter().cloned().map(KeyRequest::Key).collect();
+        self.tx.send(
+            SessionMessage {
+                id: self.id,
+                message: SessionMessages::Action(ClientAction {
+                    action: ClientActions::Request(requests)
+                })
+            }.into()
+        );
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.tx.send(
+            SessionMessage {
+                id: self.id,
+                message: SessionMessages::Disconnect,
+            }.into()
+        );
+        Running::Stop
+    }
+}
+
+/// End the stream as the server shuts down; `retry` has the browser wait
+/// before reconnecting with the last event id
+impl Handler<Shutdown> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, shutdown: Shutdown, ctx: &mut Self::Context) {
+        let retry = shutdown.retry_after.as_millis();
+        self.write(format!(": {}\nretry: {retry}\n\n", shutdown.reason()), false, ctx);
+        ctx.stop();
+    }
+}
+
+/// Handle EncodedMessage from RedisHashBroker
+impl Handler<EncodedMessage> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, message: EncodedMessage, ctx: &mut Self::Context) {
+        // always JSON, the session never asks for another encoding
+        let EncodedMessage::Text(text) = message else {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return;
+        };
+        let id = serde_json::from_str(&text).ok().and_then(|message| self.track(&message));
+        let event = match id {
+            Some(id) => format!("id: {id}\ndata: {text}\n\n"),
+            None => format!("data: {text}\n\n")
+        };
+        self.write(event, true, ctx);
+    }
+}